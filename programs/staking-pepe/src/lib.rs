@@ -1,10 +1,13 @@
 #![allow(deprecated)]
 use anchor_lang::prelude::*;
-use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::system_program;
+use anchor_spl::associated_token::{get_associated_token_address_with_program_id, AssociatedToken};
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
 use x3_pepe::{
     self,
+    program::X3Pepe,
     UserAccount,
+    MAX_LEVELS as MAX_X3_LEVELS,
 };
 
 declare_id!("9Mq2JHE2c38LTioLQYjqbLDd4jJkgftLj83VYiTYrfnJ");
@@ -15,14 +18,28 @@ pub use x3_pepe::ID as X3_PROGRAM_ID;
 const SECONDS_PER_YEAR: i128 = 31_536_000;
 // Max supported staking levels. Must match allocated space assumptions.
 const MAX_LEVELS: usize = 14;
+// Max amount tiers per staking level.
+const MAX_APY_TIERS: usize = 4;
+// Max extra lock durations per staking level.
+const MAX_LOCK_OPTIONS: usize = 4;
+// Voting weight checkpoints kept per owner, oldest are pruned first.
+pub const MAX_CHECKPOINTS: usize = 32;
+// Lock length beyond which voting weight stops growing.
+const MAX_VOTE_LOCK: u64 = 4 * SECONDS_PER_YEAR as u64;
+// Seconds after the timelock in which a passed proposal can be executed.
+const PROPOSAL_EXECUTION_WINDOW: u64 = 14 * 24 * 60 * 60;
+// Max upline depth that earns referral rewards.
+const MAX_REFERRAL_DEPTH: usize = 5;
 
 #[program]
 pub mod staking_pepe {
     use super::*;
 
+    /// Creates the pool for `staking_mint`, gated by the accounts of x3 game `x3_game_id`.
     pub fn initialize_pool(
         ctx: Context<InitializePool>,
         _pool_bump: u8,
+        x3_game_id: u64,
     ) -> Result<()> {
         let pool = &mut ctx.accounts.pool;
         pool.authority = ctx.accounts.authority.key();
@@ -30,11 +47,15 @@ pub mod staking_pepe {
         pool.staking_mint = ctx.accounts.staking_mint.key();
         pool.total_staked = 0;
         pool.bump = ctx.bumps.pool;
+        pool.staking_cap = 0;
+        pool.level_stats = Vec::new();
+        pool.x3_game_id = x3_game_id;
 
         emit!(InitializePoolEvent {
             authority: pool.authority,
             staking_mint: pool.staking_mint,
             pool: pool.key(),
+            x3_game_id,
         });
 
         Ok(())
@@ -47,23 +68,59 @@ pub mod staking_pepe {
             StakingError::Unauthorized
         );
 
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.from_authority_ata.to_account_info(),
+            mint: ctx.accounts.staking_mint.to_account_info(),
             to: ctx.accounts.stake_vault.to_account_info(),
             authority: ctx.accounts.authority.to_account_info(),
         };
         let cpi = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-        token::transfer(cpi, amount)?;
+        let received = transfer_into_vault(
+            cpi,
+            &mut ctx.accounts.stake_vault,
+            amount,
+            ctx.accounts.staking_mint.decimals,
+        )?;
+
+        ctx.accounts.pool.reward_reserve = ctx.accounts.pool.reward_reserve
+            .checked_add(received)
+            .ok_or(StakingError::MathOverflow)?;
 
         emit!(DepositRewardsEvent {
             authority: ctx.accounts.authority.key(),
             pool:  ctx.accounts.pool.key(),
-            amount,
+            amount: received,
         });
 
         Ok(())
     }
 
+    /// Credits vault tokens not backing principal, the reward reserve or owed rewards to the
+    /// reward reserve. Picks up plain transfers into the vault, e.g. x3 burn and root shares.
+    /// Permissionless.
+    pub fn sync_rewards(ctx: Context<SyncRewards>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let tracked = pool.total_staked
+            .checked_add(pool.reward_reserve)
+            .and_then(|total| total.checked_add(pool.rewards_owed))
+            .ok_or(StakingError::MathOverflow)?;
+        let untracked = ctx.accounts.stake_vault.amount.saturating_sub(tracked);
+
+        if untracked > 0 {
+            pool.reward_reserve = pool.reward_reserve
+                .checked_add(untracked)
+                .ok_or(StakingError::MathOverflow)?;
+
+            emit!(SyncRewardsEvent {
+                pool: pool.key(),
+                amount: untracked,
+                reward_reserve: pool.reward_reserve,
+            });
+        }
+
+        Ok(())
+    }
+
     pub fn init_user(ctx: Context<InitUser>) -> Result<()> {
         let user = &mut ctx.accounts.user_stake;
         user.pool = ctx.accounts.pool.key();
@@ -79,20 +136,94 @@ pub mod staking_pepe {
     }
 
     pub fn update_limits(ctx: Context<UpdateLimits>, staking_limits_by_level: Vec<StakingLimit>) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+        apply_limits(&mut ctx.accounts.pool, authority, staking_limits_by_level)
+    }
+
+    /// Sets the maximum total principal the pool accepts across all levels (0 = no cap).
+    pub fn update_pool_cap(ctx: Context<UpdateLimits>, staking_cap: u64) -> Result<()> {
+        ctx.accounts.pool.staking_cap = staking_cap;
+
+        emit!(UpdatePoolCapEvent {
+            authority: ctx.accounts.authority.key(),
+            staking_cap,
+            pool: ctx.accounts.pool.key(),
+        });
+
+        Ok(())
+    }
+
+    /// Sets the share of accrued staking rewards paid to the staker's x3 referrer chain,
+    /// in bps per depth starting with the direct referrer. Shares are paid on top of the
+    /// staker's rewards. Accrual then takes, via remaining accounts, the `ReferralRewards`
+    /// PDA of every upline, created or not, and the x3 `UserAccount` of every upline but the last.
+    pub fn update_referral_rewards(ctx: Context<UpdateLimits>, referral_bps_by_depth: Vec<u64>) -> Result<()> {
         require!(
-            staking_limits_by_level.len() <= MAX_LEVELS,
-            StakingError::TooManyLevels
+            referral_bps_by_depth.len() <= MAX_REFERRAL_DEPTH,
+            StakingError::TooManyReferralLevels
+        );
+        require!(
+            referral_bps_by_depth.iter().sum::<u64>() <= 10_000,
+            StakingError::InvalidLimits
         );
 
-        for lvl in staking_limits_by_level.iter() {
-            require!(lvl.min <= lvl.max, StakingError::InvalidLimits);
-        }
-
-        ctx.accounts.pool.staking_limits_by_level = staking_limits_by_level.clone();
+        ctx.accounts.pool.referral_bps_by_depth = referral_bps_by_depth.clone();
 
-        emit!(UpdateLimitsEvent {
+        emit!(UpdateReferralRewardsEvent {
             authority: ctx.accounts.authority.key(),
-            levels_count: staking_limits_by_level.len() as u64,
+            referral_bps_by_depth,
+            pool: ctx.accounts.pool.key(),
+        });
+
+        Ok(())
+    }
+
+    /// Creates the account an upline's referral rewards are credited to. Anyone can pay for it.
+    pub fn init_referral_rewards(ctx: Context<InitReferralRewards>, wallet: Pubkey) -> Result<()> {
+        let referral_rewards = &mut ctx.accounts.referral_rewards;
+        referral_rewards.pool = ctx.accounts.pool.key();
+        referral_rewards.wallet = wallet;
+        referral_rewards.claimable = 0;
+        referral_rewards.total_earned = 0;
+        referral_rewards.bump = ctx.bumps.referral_rewards;
+
+        Ok(())
+    }
+
+    /// Pays out the caller's claimable referral rewards from the stake vault.
+    pub fn claim_referral_rewards(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+        let amount = ctx.accounts.referral_rewards.claimable;
+        require!(amount > 0, StakingError::NothingToClaim);
+
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.initializer.as_ref(),
+            ctx.accounts.pool.staking_mint.as_ref(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.stake_vault.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
+                    to: ctx.accounts.to_wallet_ata.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                signer,
+            ),
+            amount,
+            ctx.accounts.staking_mint.decimals,
+        )?;
+
+        ctx.accounts.referral_rewards.claimable = 0;
+        ctx.accounts.pool.rewards_owed = ctx.accounts.pool.rewards_owed.saturating_sub(amount);
+
+        emit!(ClaimReferralRewardsEvent {
+            wallet: ctx.accounts.wallet.key(),
+            amount,
             pool: ctx.accounts.pool.key(),
         });
 
@@ -115,146 +246,98 @@ pub mod staking_pepe {
         Ok(())
     }
 
+    /// Stakes `amount` on `level` with the chosen lock: 0 for the level's own period,
+    /// `n` for `lock_options[n - 1]`. Adding to a position whose lock has ended relocks
+    /// the new total under the chosen lock.
     pub fn stake(
         ctx: Context<Stake>,
         amount: u64,
-        level: u8
+        level: u8,
+        lock_option: u8,
     ) -> Result<()> {
-        require!(
-            level < ctx.accounts.pool.staking_limits_by_level.len() as u8,
-            StakingError::LevelOutOfRange
-        );
-
-        let level_limits = &ctx.accounts.pool.staking_limits_by_level[level as usize];
-        let level_idx = level as usize;
-        let current_time = Clock::get()?.unix_timestamp as u64;
-
-        // Ensure staking_by_level vector is large enough
-        while ctx.accounts.user_stake.staking_by_level.len() <= level_idx {
-            ctx.accounts.user_stake.staking_by_level.push(StakingInfo {
-                amount_staked: 0,
-                rewards_accrued: 0,
-                last_update_ts: 0,
-                start_staking_time: 0,
-                boost_rewards_total: 0,
-                cycle_used: 0,
-            });
-        }
-
-        let already_stake = ctx.accounts.user_stake.staking_by_level[level_idx].amount_staked;
-        let is_new_stake = already_stake == 0;
-        let used_cycles = ctx.accounts.user_stake.staking_by_level[level_idx].cycle_used;
-        let is_restake = is_new_stake && used_cycles > 0;
-
-        if is_new_stake {
-            // New stake or restake: requires cycle and must meet min limit
-            let cycles = get_x3_cycles(
-                &ctx.accounts.external_state,
-                ctx.accounts.user_stake.owner,
-                level,
-            )?;
-
-            require!(
-                amount >= level_limits.min && amount <= level_limits.max,
-                StakingError::AmountOutOfLimits
-            );
-
-            require!(
-                (cycles as u64) > used_cycles,
-                StakingError::NoStakingRights
-            );
-            ctx.accounts.user_stake.staking_by_level[level_idx].cycle_used = used_cycles
-                .checked_add(1)
-                .ok_or(StakingError::MathOverflow)?;
-        } else {
-            // Adding to existing stake: check period hasn't ended and new total doesn't exceed max
-            let start_time = ctx.accounts.user_stake.staking_by_level[level_idx].start_staking_time;
-            let period_end_time = start_time
-                .checked_add(level_limits.period)
-                .ok_or(StakingError::MathOverflow)?;
-
-            require!(
-                current_time < period_end_time,
-                StakingError::StakingPeriodEnded
-            );
-
-            let new_total = already_stake
-                .checked_add(amount)
-                .ok_or(StakingError::MathOverflow)?;
-            require!(
-                new_total <= level_limits.max,
-                StakingError::AmountOutOfLimits
-            );
-        }
-
-        if !is_new_stake {
-            let accrued = accrue_rewards_internal(
-                &ctx.accounts.pool,
-                &mut ctx.accounts.user_stake,
-                &ctx.accounts.external_state,
-            )?;
-
-            // Emit accrual events
-            for reward in accrued {
-                emit!(AccrueRewardsEvent {
-                owner: ctx.accounts.owner.key(),
-                pool: ctx.accounts.pool.key(),
-                level: reward.level,
-                amount: reward.accrued_amount,
-                total_rewards: reward.total_rewards,
-                boost_rewards: reward.boost_rewards,
-                total_boost_rewards: reward.total_boost_rewards,
-            });
-            }
-        }
-
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.from_user_ata.to_account_info(),
+            mint: ctx.accounts.staking_mint.to_account_info(),
             to: ctx.accounts.stake_vault.to_account_info(),
             authority: ctx.accounts.owner.to_account_info(),
         };
         let cpi = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-        token::transfer(cpi, amount)?;
+        // Only what reaches the vault after any transfer fee is staked
+        let received = transfer_into_vault(
+            cpi,
+            &mut ctx.accounts.stake_vault,
+            amount,
+            ctx.accounts.staking_mint.decimals,
+        )?;
 
-        if is_new_stake {
-            ctx.accounts.user_stake.staking_by_level[level_idx].start_staking_time = current_time;
-        }
-        ctx.accounts.user_stake.staking_by_level[level_idx].amount_staked = ctx.accounts.user_stake.staking_by_level[level_idx]
-            .amount_staked
-            .checked_add(amount)
-            .ok_or(StakingError::MathOverflow)?;
-        ctx.accounts.user_stake.staking_by_level[level_idx].last_update_ts = current_time;
+        stake_internal(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.external_state,
+            ctx.remaining_accounts,
+            received,
+            level,
+            lock_option,
+        )?;
 
-        ctx.accounts.pool.total_staked = ctx.accounts.pool
-            .total_staked
-            .checked_add(amount)
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            ctx.bumps.vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )?;
+
+        Ok(())
+    }
+
+    /// Stakes `amount` of the owner's unclaimed x3 balance on `level`. The x3 program moves
+    /// the tokens from its vault straight into `stake_vault`, and the usual eligibility and
+    /// limit checks of `stake` apply.
+    pub fn stake_from_x3(
+        ctx: Context<StakeFromX3>,
+        amount: u64,
+        level: u8,
+        lock_option: u8,
+    ) -> Result<()> {
+        let vault_before = ctx.accounts.stake_vault.amount;
+        x3_pepe::cpi::claim_to(
+            CpiContext::new(
+                ctx.accounts.x3_program.to_account_info(),
+                x3_pepe::cpi::accounts::ClaimTo {
+                    global_state: ctx.accounts.x3_global_state.to_account_info(),
+                    user_account: ctx.accounts.external_state.to_account_info(),
+                    user_wallet: ctx.accounts.owner.to_account_info(),
+                    destination_token_account: ctx.accounts.stake_vault.to_account_info(),
+                    vault_token_account: Some(ctx.accounts.x3_vault_token_account.to_account_info()),
+                    token_mint: Some(ctx.accounts.staking_mint.to_account_info()),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        ctx.accounts.external_state.reload()?;
+        ctx.accounts.stake_vault.reload()?;
+        // Only what reaches the vault after any transfer fee is staked
+        let received = ctx.accounts.stake_vault.amount
+            .checked_sub(vault_before)
             .ok_or(StakingError::MathOverflow)?;
 
-        // Emit appropriate event based on staking type
-        if is_restake {
-            emit!(RestakeEvent {
-                owner: ctx.accounts.owner.key(),
-                pool: ctx.accounts.pool.key(),
-                level,
-                amount,
-            });
-        } else if is_new_stake {
-            emit!(NewStakeEvent {
-                owner: ctx.accounts.owner.key(),
-                pool: ctx.accounts.pool.key(),
-                level,
-                amount,
-            });
-        } else {
-            let total_amount = ctx.accounts.user_stake.staking_by_level[level_idx].amount_staked;
-            emit!(AddToStakeEvent {
-                owner: ctx.accounts.owner.key(),
-                pool: ctx.accounts.pool.key(),
-                level,
-                amount,
-                total_amount,
-            });
-        }
+        stake_internal(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.external_state,
+            ctx.remaining_accounts,
+            received,
+            level,
+            lock_option,
+        )?;
+
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            ctx.bumps.vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )?;
 
         Ok(())
     }
@@ -279,7 +362,7 @@ pub mod staking_pepe {
         let current_time = Clock::get()?.unix_timestamp as u64;
         let start_staking_time = level_staking.start_staking_time;
         let period_end_time = start_staking_time
-            .checked_add(level_limits.period)
+            .checked_add(level_limits.period_for(level_staking.lock_option))
             .ok_or(StakingError::MathOverflow)?;
 
         // Require that start_staking_time + period <= current_time (period has ended)
@@ -287,11 +370,16 @@ pub mod staking_pepe {
             period_end_time <= current_time,
             StakingError::StakingPeriodNotEnded
         );
+        require!(
+            level_limits.unbonding_period == 0,
+            StakingError::UnbondingRequired
+        );
 
         let accrued = accrue_rewards_internal(
-            &ctx.accounts.pool,
+            &mut ctx.accounts.pool,
             &mut ctx.accounts.user_stake,
             &ctx.accounts.external_state,
+            ctx.remaining_accounts,
         )?;
 
         // Emit accrual events
@@ -309,6 +397,7 @@ pub mod staking_pepe {
 
         let level_staking = &mut ctx.accounts.user_stake.staking_by_level[level_idx];
         let rewards = level_staking.rewards_accrued;
+        let boost_rewards = level_staking.take_unpaid_boost();
 
         let seeds = &[
             b"pool",
@@ -322,28 +411,40 @@ pub mod staking_pepe {
             .checked_add(rewards)
             .ok_or(StakingError::MathOverflow)?;
 
-        token::transfer(
+        token_interface::transfer_checked(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
+                TransferChecked {
                     from: ctx.accounts.stake_vault.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
                     to: ctx.accounts.to_user_stake_ata.to_account_info(),
                     authority: ctx.accounts.pool.to_account_info(),
                 },
                 signer,
             ),
             total_amount,
+            ctx.accounts.staking_mint.decimals,
         )?;
 
         ctx.accounts.pool.total_staked = ctx.accounts.pool.total_staked
             .checked_sub(amount_staked)
             .ok_or(StakingError::MathOverflow)?;
+        ctx.accounts.pool.record_unstake(level_idx, amount_staked, rewards, boost_rewards, true)?;
 
         level_staking.amount_staked = 0;
         level_staking.rewards_accrued = 0;
         level_staking.start_staking_time = 0;
+        level_staking.unbonding_start = 0;
+        level_staking.last_period_end = period_end_time;
         level_staking.last_update_ts = Clock::get()?.unix_timestamp as u64;
 
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            ctx.bumps.vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )?;
+
         emit!(UnstakeEvent {
             owner: ctx.accounts.user_stake.owner,
             pool: ctx.accounts.pool.key(),
@@ -355,11 +456,60 @@ pub mod staking_pepe {
         Ok(())
     }
 
-    pub fn update_user_rewards(ctx: Context<UpdateUserRewards>) -> Result<()> {
+    /// Withdraws part of the principal of a level after its staking period has ended.
+    /// Accrued rewards are paid out in full. With `relock` the remainder starts a new
+    /// lock period and keeps earning without consuming a cycle right, extending the loyalty
+    /// streak only within the grace window; otherwise it stays unlocked and idle until it
+    /// is unstaked or topped up with `stake`.
+    pub fn partial_unstake(ctx: Context<Unstake>, level: u8, amount: u64, relock: bool) -> Result<()> {
+        require!(
+            level < ctx.accounts.user_stake.staking_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+
+        let level_idx = level as usize;
+        let level_staking = &ctx.accounts.user_stake.staking_by_level[level_idx];
+        let amount_staked = level_staking.amount_staked;
+        require!(amount_staked > 0, StakingError::NothingToUnstake);
+        require!(
+            amount > 0 && amount < amount_staked,
+            StakingError::InvalidUnstakeAmount
+        );
+
+        require!(
+            level < ctx.accounts.pool.staking_limits_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+        let level_limits = &ctx.accounts.pool.staking_limits_by_level[level_idx];
+        let current_time = Clock::get()?.unix_timestamp as u64;
+        let period_end_time = level_staking
+            .start_staking_time
+            .checked_add(level_limits.period_for(level_staking.lock_option))
+            .ok_or(StakingError::MathOverflow)?;
+        let grace_period = level_limits.loyalty.grace_period;
+
+        require!(
+            period_end_time <= current_time,
+            StakingError::StakingPeriodNotEnded
+        );
+        require!(
+            level_limits.unbonding_period == 0,
+            StakingError::UnbondingRequired
+        );
+
+        let remaining = amount_staked - amount;
+        if relock {
+            require!(
+                remaining >= level_limits.min && remaining <= level_limits.max,
+                StakingError::AmountOutOfLimits
+            );
+        }
+
         let accrued = accrue_rewards_internal(
-            &ctx.accounts.pool,
+            &mut ctx.accounts.pool,
             &mut ctx.accounts.user_stake,
             &ctx.accounts.external_state,
+            ctx.remaining_accounts,
         )?;
 
         // Emit accrual events
@@ -375,62 +525,1195 @@ pub mod staking_pepe {
             });
         }
 
-        Ok(())
-    }
-}
-
-fn accrue_rewards_internal(
-    pool: &Account<Pool>,
-    user: &mut Account<UserStake>,
-    external_account: &Account<UserAccount>,
-) -> Result<Vec<AccruedReward>> {
-    // Returns Vec<AccruedReward> with reward information for each level
-    // Ensure wallet == user.owner
-    require_keys_eq!(external_account.wallet, user.owner, StakingError::InvalidExternalStateData);
-
-    // Convert boosters (closed_cycles) to Vec<(u64, u8)> format
-    let boosters: Vec<(u64, u8)> = external_account.closed_cycles
-        .iter()
-        .map(|cycle| (cycle.created_time, cycle.level))
-        .collect();
+        let level_staking = &mut ctx.accounts.user_stake.staking_by_level[level_idx];
+        let rewards = level_staking.rewards_accrued;
+        let boost_rewards = level_staking.take_unpaid_boost();
 
-    let now = Clock::get()?.unix_timestamp as u64;
-    let mut accrued_rewards: Vec<AccruedReward> = Vec::new();
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.initializer.as_ref(),
+            ctx.accounts.pool.staking_mint.as_ref(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
 
-    // Process each level staking
-    for (level, level_staking) in user.staking_by_level.iter_mut().enumerate() {
-        // Check if staking period has ended (start_staking_time + period > now)
-        let level_limits = if level < pool.staking_limits_by_level.len() {
-            &pool.staking_limits_by_level[level]
-        } else {
-            continue; // Skip if no limits defined for this level
-        };
+        let total_amount = amount
+            .checked_add(rewards)
+            .ok_or(StakingError::MathOverflow)?;
 
-        if level_staking.amount_staked == 0 {
-            continue;
-        }
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.stake_vault.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
+                    to: ctx.accounts.to_user_stake_ata.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                signer,
+            ),
+            total_amount,
+            ctx.accounts.staking_mint.decimals,
+        )?;
 
-        let period_end_time = level_staking
-            .start_staking_time
-            .checked_add(level_limits.period)
+        ctx.accounts.pool.total_staked = ctx.accounts.pool.total_staked
+            .checked_sub(amount)
             .ok_or(StakingError::MathOverflow)?;
-        let reward_start_time = level_staking.last_update_ts;
+        ctx.accounts.pool.record_unstake(level_idx, amount, rewards, boost_rewards, false)?;
 
-        if period_end_time <= reward_start_time {
-            continue; // Period has ended, no more rewards
+        level_staking.amount_staked = remaining;
+        level_staking.rewards_accrued = 0;
+        level_staking.last_period_end = period_end_time;
+        if relock {
+            // Same grace window as a restake through `stake`
+            level_staking.streak = level_staking.restake_streak(grace_period, current_time)?;
+            level_staking.start_staking_time = current_time;
         }
+        level_staking.last_update_ts = current_time;
 
-        // Calculate time delta from max(last_update_ts, close_level_time)
-        if now <= reward_start_time {
-            continue;
-        }
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            ctx.bumps.vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )?;
+
+        emit!(PartialUnstakeEvent {
+            owner: ctx.accounts.user_stake.owner,
+            pool: ctx.accounts.pool.key(),
+            level,
+            amount,
+            rewards,
+            remaining,
+            relocked: relock,
+        });
+
+        Ok(())
+    }
+
+    /// Starts the unbonding cooldown for a level whose staking period has ended.
+    /// Rewards are accrued one last time and stop from here on; tokens can be
+    /// released with `withdraw` once the level's `unbonding_period` has passed.
+    pub fn request_unstake(ctx: Context<RequestUnstake>, level: u8) -> Result<()> {
+        require!(
+            level < ctx.accounts.user_stake.staking_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+        require!(
+            level < ctx.accounts.pool.staking_limits_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+
+        let level_idx = level as usize;
+        let level_staking = &ctx.accounts.user_stake.staking_by_level[level_idx];
+        require!(level_staking.amount_staked > 0, StakingError::NothingToUnstake);
+        require!(
+            level_staking.unbonding_start == 0,
+            StakingError::UnbondingAlreadyRequested
+        );
+
+        let level_limits = &ctx.accounts.pool.staking_limits_by_level[level_idx];
+        require!(
+            level_limits.unbonding_period > 0,
+            StakingError::UnbondingNotEnabled
+        );
+        let unbonding_period = level_limits.unbonding_period;
+        let current_time = Clock::get()?.unix_timestamp as u64;
+        let period_end_time = level_staking
+            .start_staking_time
+            .checked_add(level_limits.period_for(level_staking.lock_option))
+            .ok_or(StakingError::MathOverflow)?;
+
+        require!(
+            period_end_time <= current_time,
+            StakingError::StakingPeriodNotEnded
+        );
+
+        let accrued = accrue_rewards_internal(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.external_state,
+            ctx.remaining_accounts,
+        )?;
+
+        // Emit accrual events
+        for reward in accrued {
+            emit!(AccrueRewardsEvent {
+                owner: ctx.accounts.user_stake.owner,
+                pool: ctx.accounts.pool.key(),
+                level: reward.level,
+                amount: reward.accrued_amount,
+                total_rewards: reward.total_rewards,
+                boost_rewards: reward.boost_rewards,
+                total_boost_rewards: reward.total_boost_rewards,
+            });
+        }
+
+        let owner = ctx.accounts.user_stake.owner;
+        let level_staking = &mut ctx.accounts.user_stake.staking_by_level[level_idx];
+        level_staking.unbonding_start = current_time;
+
+        emit!(UnstakeRequestedEvent {
+            owner,
+            pool: ctx.accounts.pool.key(),
+            level,
+            amount: level_staking.amount_staked,
+            rewards: level_staking.rewards_accrued,
+            withdrawable_at: current_time
+                .checked_add(unbonding_period)
+                .ok_or(StakingError::MathOverflow)?,
+        });
+
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            ctx.bumps.vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )?;
+
+        Ok(())
+    }
+
+    /// Releases principal and rewards of a level once its unbonding cooldown has ended.
+    pub fn withdraw(ctx: Context<Unstake>, level: u8) -> Result<()> {
+        require!(
+            level < ctx.accounts.user_stake.staking_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+        require!(
+            level < ctx.accounts.pool.staking_limits_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+
+        let level_idx = level as usize;
+        let level_staking = &ctx.accounts.user_stake.staking_by_level[level_idx];
+        let amount_staked = level_staking.amount_staked;
+        require!(amount_staked > 0, StakingError::NothingToUnstake);
+        require!(
+            level_staking.unbonding_start != 0,
+            StakingError::UnbondingNotRequested
+        );
+
+        let level_limits = &ctx.accounts.pool.staking_limits_by_level[level_idx];
+        let current_time = Clock::get()?.unix_timestamp as u64;
+        let unbonding_end_time = level_staking
+            .unbonding_start
+            .checked_add(level_limits.unbonding_period)
+            .ok_or(StakingError::MathOverflow)?;
+
+        require!(
+            unbonding_end_time <= current_time,
+            StakingError::UnbondingNotEnded
+        );
+
+        let rewards = level_staking.rewards_accrued;
+
+        let seeds = &[
+            b"pool",
+            ctx.accounts.pool.initializer.as_ref(),
+            ctx.accounts.pool.staking_mint.as_ref(),
+            &[ctx.accounts.pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let total_amount = amount_staked
+            .checked_add(rewards)
+            .ok_or(StakingError::MathOverflow)?;
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.stake_vault.to_account_info(),
+                    mint: ctx.accounts.staking_mint.to_account_info(),
+                    to: ctx.accounts.to_user_stake_ata.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                signer,
+            ),
+            total_amount,
+            ctx.accounts.staking_mint.decimals,
+        )?;
+
+        ctx.accounts.pool.total_staked = ctx.accounts.pool.total_staked
+            .checked_sub(amount_staked)
+            .ok_or(StakingError::MathOverflow)?;
+        let level_staking = &mut ctx.accounts.user_stake.staking_by_level[level_idx];
+        let boost_rewards = level_staking.take_unpaid_boost();
+        ctx.accounts.pool.record_unstake(level_idx, amount_staked, rewards, boost_rewards, true)?;
+
+        level_staking.amount_staked = 0;
+        level_staking.rewards_accrued = 0;
+        level_staking.start_staking_time = 0;
+        level_staking.unbonding_start = 0;
+        // The position was only free to restake once unbonding ended
+        level_staking.last_period_end = unbonding_end_time;
+        level_staking.last_update_ts = current_time;
+
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            ctx.bumps.vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )?;
+
+        emit!(UnstakeEvent {
+            owner: ctx.accounts.user_stake.owner,
+            pool: ctx.accounts.pool.key(),
+            level,
+            amount: amount_staked,
+            rewards,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the owner's voting weight as of `slot`, from the latest checkpoint at or before it.
+    pub fn get_voting_weight(ctx: Context<GetVotingWeight>, slot: u64) -> Result<u64> {
+        ctx.accounts.vote_ledger.weight_at(slot)
+    }
+
+    /// Returns the owner's voting weight as of unix time `timestamp`, from the latest checkpoint
+    /// recorded at or before it.
+    pub fn get_voting_weight_at_time(ctx: Context<GetVotingWeight>, timestamp: u64) -> Result<u64> {
+        ctx.accounts.vote_ledger.weight_at_time(timestamp)
+    }
+
+    /// Checkpoints the owner's weight again so the lock bonus decays as the lock runs down,
+    /// without changing the position. Owner only, so nobody else can fill the ledger and
+    /// prune the checkpoints an open proposal snapshots.
+    pub fn refresh_voting_weight(ctx: Context<RefreshVotingWeight>) -> Result<()> {
+        let bump = ctx.accounts.vote_ledger.bump;
+        checkpoint_voting_weight(
+            &mut ctx.accounts.vote_ledger,
+            bump,
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake,
+        )
+    }
+
+    /// Creates the governance config for a pool. To hand parameters over to stakers, the pool
+    /// authority and the x3 owner are then moved to the governance PDA.
+    pub fn init_governance(
+        ctx: Context<InitGovernance>,
+        voting_period: u64,
+        timelock: u64,
+        quorum: u64,
+        proposal_threshold: u64,
+    ) -> Result<()> {
+        require!(voting_period > 0, StakingError::InvalidGovernanceConfig);
+
+        let governance = &mut ctx.accounts.governance;
+        governance.pool = ctx.accounts.pool.key();
+        governance.voting_period = voting_period;
+        governance.timelock = timelock;
+        governance.quorum = quorum;
+        governance.proposal_threshold = proposal_threshold;
+        governance.proposal_count = 0;
+        governance.bump = ctx.bumps.governance;
+
+        emit!(InitGovernanceEvent {
+            governance: governance.key(),
+            pool: governance.pool,
+            voting_period,
+            timelock,
+            quorum,
+            proposal_threshold,
+        });
+
+        Ok(())
+    }
+
+    /// Opens a proposal. The proposer needs `proposal_threshold` voting weight as of the
+    /// previous slot, which is also the snapshot every vote is weighed at.
+    pub fn create_proposal(ctx: Context<CreateProposal>, action: ProposalAction) -> Result<()> {
+        match &action {
+            ProposalAction::UpdateLimits { staking_limits_by_level } => require!(
+                staking_limits_by_level.len() <= MAX_LEVELS,
+                StakingError::TooManyLevels
+            ),
+            ProposalAction::UpdatePrices { prices } => require!(
+                !prices.is_empty() && prices.len() <= MAX_X3_LEVELS,
+                StakingError::InvalidProposal
+            ),
+        }
+
+        let clock = Clock::get()?;
+        let snapshot_slot = clock.slot.saturating_sub(1);
+        let weight = ctx.accounts.vote_ledger.weight_at(snapshot_slot)?;
+        require!(
+            weight >= ctx.accounts.governance.proposal_threshold,
+            StakingError::ProposalThresholdNotMet
+        );
+
+        let governance = &mut ctx.accounts.governance;
+        let id = governance.proposal_count;
+        governance.proposal_count = id
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+
+        let voting_ends_at = (clock.unix_timestamp as u64)
+            .checked_add(governance.voting_period)
+            .ok_or(StakingError::MathOverflow)?;
+
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.governance = governance.key();
+        proposal.id = id;
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.action = action;
+        proposal.snapshot_slot = snapshot_slot;
+        proposal.voting_ends_at = voting_ends_at;
+        proposal.votes_for = 0;
+        proposal.votes_against = 0;
+        proposal.executed = false;
+        proposal.bump = ctx.bumps.proposal;
+
+        emit!(ProposalCreatedEvent {
+            governance: proposal.governance,
+            proposal: proposal.key(),
+            id,
+            proposer: proposal.proposer,
+            snapshot_slot,
+            voting_ends_at,
+        });
+
+        Ok(())
+    }
+
+    /// Votes on an open proposal with the voter's weight at the proposal snapshot.
+    pub fn cast_vote(ctx: Context<CastVote>, support: bool) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp as u64;
+        let proposal = &mut ctx.accounts.proposal;
+        require!(now < proposal.voting_ends_at, StakingError::VotingClosed);
+
+        let weight = ctx.accounts.vote_ledger.weight_at(proposal.snapshot_slot)?;
+        require!(weight > 0, StakingError::NoVotingWeight);
+
+        if support {
+            proposal.votes_for = proposal.votes_for
+                .checked_add(weight)
+                .ok_or(StakingError::MathOverflow)?;
+        } else {
+            proposal.votes_against = proposal.votes_against
+                .checked_add(weight)
+                .ok_or(StakingError::MathOverflow)?;
+        }
+
+        let vote_record = &mut ctx.accounts.vote_record;
+        vote_record.proposal = proposal.key();
+        vote_record.voter = ctx.accounts.voter.key();
+        vote_record.support = support;
+        vote_record.weight = weight;
+
+        emit!(VoteCastEvent {
+            proposal: proposal.key(),
+            voter: vote_record.voter,
+            support,
+            weight,
+        });
+
+        Ok(())
+    }
+
+    /// Executes a passed proposal once voting and the timelock are over, within
+    /// `PROPOSAL_EXECUTION_WINDOW`. Limits are validated and applied like `update_limits`
+    /// while the governance PDA is the pool authority, prices through x3 `update_prices`
+    /// with the governance PDA signing as x3 owner.
+    pub fn execute_proposal(ctx: Context<ExecuteProposal>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp as u64;
+        let governance = &ctx.accounts.governance;
+        let proposal = &mut ctx.accounts.proposal;
+        require!(!proposal.executed, StakingError::ProposalAlreadyExecuted);
+
+        let executable_at = proposal
+            .voting_ends_at
+            .checked_add(governance.timelock)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(now >= executable_at, StakingError::TimelockNotEnded);
+        require!(
+            now < executable_at.saturating_add(PROPOSAL_EXECUTION_WINDOW),
+            StakingError::ProposalExpired
+        );
+        require!(
+            proposal.votes_for > proposal.votes_against
+                && proposal.votes_for >= governance.quorum,
+            StakingError::ProposalNotPassed
+        );
+
+        proposal.executed = true;
+
+        let seeds = &[
+            b"governance",
+            governance.pool.as_ref(),
+            &[governance.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        match proposal.action.clone() {
+            ProposalAction::UpdateLimits { staking_limits_by_level } => {
+                require_keys_eq!(
+                    ctx.accounts.pool.authority,
+                    governance.key(),
+                    StakingError::Unauthorized
+                );
+                apply_limits(&mut ctx.accounts.pool, governance.key(), staking_limits_by_level)?;
+            }
+            ProposalAction::UpdatePrices { prices } => {
+                let (Some(x3_program), Some(x3_global_state), Some(x3_root_account)) = (
+                    ctx.accounts.x3_program.as_ref(),
+                    ctx.accounts.x3_global_state.as_ref(),
+                    ctx.accounts.x3_root_account.as_ref(),
+                ) else {
+                    return err!(StakingError::MissingExecutionAccount);
+                };
+                x3_pepe::cpi::update_prices(
+                    CpiContext::new_with_signer(
+                        x3_program.to_account_info(),
+                        x3_pepe::cpi::accounts::UpdatePrices {
+                            global_state: x3_global_state.to_account_info(),
+                            root_account: x3_root_account.to_account_info(),
+                            owner: governance.to_account_info(),
+                        },
+                        signer,
+                    ),
+                    prices,
+                )?;
+            }
+        }
+
+        emit!(ProposalExecutedEvent {
+            governance: governance.key(),
+            proposal: proposal.key(),
+            id: proposal.id,
+        });
+
+        Ok(())
+    }
+
+    /// Moves a level's position, with principal, accrued rewards and lock timing, to another
+    /// wallet. Both owners sign, and the recipient needs a free x3 staking right for the level,
+    /// which the transfer consumes like a new stake.
+    pub fn transfer_position(ctx: Context<TransferPosition>, level: u8) -> Result<()> {
+        require_keys_neq!(
+            ctx.accounts.from_owner.key(),
+            ctx.accounts.to_owner.key(),
+            StakingError::InvalidTransfer
+        );
+        require!(
+            level < ctx.accounts.from_user_stake.staking_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+        require!(
+            level < ctx.accounts.pool.staking_limits_by_level.len() as u8,
+            StakingError::LevelOutOfRange
+        );
+
+        let level_idx = level as usize;
+        let from_staking = &ctx.accounts.from_user_stake.staking_by_level[level_idx];
+        require!(from_staking.amount_staked > 0, StakingError::NothingToUnstake);
+        require!(
+            from_staking.unbonding_start == 0,
+            StakingError::UnbondingAlreadyRequested
+        );
+
+        let to_user_stake = &mut ctx.accounts.to_user_stake;
+        if to_user_stake.owner == Pubkey::default() {
+            to_user_stake.pool = ctx.accounts.pool.key();
+            to_user_stake.owner = ctx.accounts.to_owner.key();
+            to_user_stake.staking_by_level = Vec::new();
+        }
+        while to_user_stake.staking_by_level.len() <= level_idx {
+            to_user_stake.staking_by_level.push(StakingInfo::default());
+        }
+        let to_staking = &to_user_stake.staking_by_level[level_idx];
+        require!(
+            to_staking.amount_staked == 0 && to_staking.unbonding_start == 0,
+            StakingError::PositionSlotOccupied
+        );
+
+        // Recipient must be eligible exactly as for a new stake
+        let cycles = get_x3_cycles(
+            &ctx.accounts.to_external_state,
+            to_user_stake.owner,
+            level,
+        )?;
+        require!(
+            (cycles as u64) > to_staking.cycle_used,
+            StakingError::NoStakingRights
+        );
+
+        let accrued = accrue_rewards_internal(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.from_user_stake,
+            &ctx.accounts.from_external_state,
+            ctx.remaining_accounts,
+        )?;
+
+        // Emit accrual events
+        for reward in accrued {
+            emit!(AccrueRewardsEvent {
+                owner: ctx.accounts.from_user_stake.owner,
+                pool: ctx.accounts.pool.key(),
+                level: reward.level,
+                amount: reward.accrued_amount,
+                total_rewards: reward.total_rewards,
+                boost_rewards: reward.boost_rewards,
+                total_boost_rewards: reward.total_boost_rewards,
+            });
+        }
+
+        let current_time = Clock::get()?.unix_timestamp as u64;
+        let from_staking = &mut ctx.accounts.from_user_stake.staking_by_level[level_idx];
+        let amount = from_staking.amount_staked;
+        let rewards = from_staking.rewards_accrued;
+        let start_staking_time = from_staking.start_staking_time;
+        let last_update_ts = from_staking.last_update_ts;
+        let lock_option = from_staking.lock_option;
+        let streak = from_staking.streak;
+        let last_period_end = from_staking.last_period_end;
+        let unpaid_boost = from_staking.take_unpaid_boost();
+
+        // The loyalty streak moves with the position
+        from_staking.amount_staked = 0;
+        from_staking.rewards_accrued = 0;
+        from_staking.start_staking_time = 0;
+        from_staking.streak = 0;
+        from_staking.last_period_end = 0;
+        from_staking.last_update_ts = current_time;
+
+        let to_staking = &mut ctx.accounts.to_user_stake.staking_by_level[level_idx];
+        to_staking.cycle_used = to_staking.cycle_used
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+        to_staking.amount_staked = amount;
+        to_staking.rewards_accrued = rewards;
+        to_staking.start_staking_time = start_staking_time;
+        to_staking.last_update_ts = last_update_ts;
+        to_staking.lock_option = lock_option;
+        to_staking.streak = streak;
+        to_staking.last_period_end = last_period_end;
+        to_staking.boost_rewards_total = to_staking.boost_rewards_total
+            .checked_add(unpaid_boost)
+            .ok_or(StakingError::MathOverflow)?;
+
+        checkpoint_voting_weight(
+            &mut ctx.accounts.from_vote_ledger,
+            ctx.bumps.from_vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.from_user_stake,
+        )?;
+        checkpoint_voting_weight(
+            &mut ctx.accounts.to_vote_ledger,
+            ctx.bumps.to_vote_ledger,
+            &ctx.accounts.pool,
+            &ctx.accounts.to_user_stake,
+        )?;
+
+        emit!(TransferPositionEvent {
+            pool: ctx.accounts.pool.key(),
+            from: ctx.accounts.from_owner.key(),
+            to: ctx.accounts.to_owner.key(),
+            level,
+            amount,
+            rewards,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_user_rewards(ctx: Context<UpdateUserRewards>) -> Result<()> {
+        let accrued = accrue_rewards_internal(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.external_state,
+            ctx.remaining_accounts,
+        )?;
+
+        // Emit accrual events
+        for reward in accrued {
+            emit!(AccrueRewardsEvent {
+                owner: ctx.accounts.user_stake.owner,
+                pool: ctx.accounts.pool.key(),
+                level: reward.level,
+                amount: reward.accrued_amount,
+                total_rewards: reward.total_rewards,
+                boost_rewards: reward.boost_rewards,
+                total_boost_rewards: reward.total_boost_rewards,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Accrues rewards for `count` positions in one instruction. Remaining accounts start with
+    /// `count` (`UserStake`, x3 `UserAccount`) pairs, followed by any referral accounts.
+    pub fn update_user_rewards_batch<'link, 'info>(
+        ctx: Context<'_, '_, 'link, 'info, UpdateUserRewardsBatch<'info>>,
+        count: u8,
+    ) -> Result<()>
+    where
+        'link: 'info,
+    {
+        let pairs_len = count as usize * 2;
+        require!(
+            ctx.remaining_accounts.len() >= pairs_len,
+            StakingError::MissingBatchAccounts
+        );
+        let (pairs, referral_accounts) = ctx.remaining_accounts.split_at(pairs_len);
+        let pool_key = ctx.accounts.pool.key();
+
+        for pair in pairs.chunks(2) {
+            let mut user_stake: Account<UserStake> = Account::try_from(&pair[0])?;
+            require!(pair[0].is_writable, StakingError::InvalidBatchAccount);
+            require_keys_eq!(user_stake.pool, pool_key, StakingError::InvalidBatchAccount);
+            let (user_stake_key, _) = Pubkey::find_program_address(
+                &[b"user", pool_key.as_ref(), user_stake.owner.as_ref()],
+                &ID,
+            );
+            require_keys_eq!(user_stake.key(), user_stake_key, StakingError::InvalidBatchAccount);
+
+            let external_state: Account<UserAccount> = Account::try_from(&pair[1])
+                .map_err(|_| StakingError::InvalidExternalStateOwner)?;
+            require_keys_eq!(
+                external_state.key(),
+                ctx.accounts.pool.x3_user_key(&user_stake.owner),
+                StakingError::InvalidExternalStateData
+            );
+
+            let accrued = accrue_rewards_internal(
+                &mut ctx.accounts.pool,
+                &mut user_stake,
+                &external_state,
+                referral_accounts,
+            )?;
+
+            // Emit accrual events
+            for reward in accrued {
+                emit!(AccrueRewardsEvent {
+                    owner: user_stake.owner,
+                    pool: pool_key,
+                    level: reward.level,
+                    amount: reward.accrued_amount,
+                    total_rewards: reward.total_rewards,
+                    boost_rewards: reward.boost_rewards,
+                    total_boost_rewards: reward.total_boost_rewards,
+                });
+            }
+
+            user_stake.exit(&ID)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites a pool created before per-level stats, caps and referral rewards in the
+    /// current layout. Authority only, and required before any other pool instruction.
+    /// Vault tokens not backing principal seed the reward reserve; `migrate_user` moves
+    /// each stake's accrued rewards from there to what is owed.
+    pub fn migrate_pool(ctx: Context<MigratePool>) -> Result<()> {
+        let info = ctx.accounts.pool.to_account_info();
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(
+                is_legacy_layout(&data, Pool::DISCRIMINATOR, Pool::LEN),
+                StakingError::AlreadyMigrated
+            );
+            LegacyPool::deserialize(&mut &data[8..])?
+        };
+        require_keys_eq!(
+            legacy.authority,
+            ctx.accounts.authority.key(),
+            StakingError::Unauthorized
+        );
+        let pool_key = Pubkey::create_program_address(
+            &[
+                b"pool",
+                legacy.initializer.as_ref(),
+                legacy.staking_mint.as_ref(),
+                &[legacy.bump],
+            ],
+            &ID,
+        )
+        .map_err(|_| StakingError::InvalidMigrationAccount)?;
+        require_keys_eq!(info.key(), pool_key, StakingError::InvalidMigrationAccount);
+        require_keys_eq!(
+            ctx.accounts.stake_vault.key(),
+            get_associated_token_address_with_program_id(
+                &pool_key,
+                &legacy.staking_mint,
+                &ctx.accounts.token_program.key(),
+            ),
+            StakingError::InvalidMigrationAccount
+        );
+
+        let mut pool = Pool::from(legacy);
+        pool.reward_reserve = ctx.accounts.stake_vault.amount.saturating_sub(pool.total_staked);
+        let old_size = rewrite_account(
+            &info,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+            Pool::LEN,
+            &pool,
+        )?;
+
+        emit!(AccountMigratedEvent {
+            account: pool_key,
+            old_size: old_size as u32,
+            new_size: Pool::LEN as u32,
+        });
+        Ok(())
+    }
+
+    /// Rewrites a `UserStake` from the original layout and counts its open positions in the
+    /// pool's level stats. Permissionless, the payer covers any extra rent. Unmigrated
+    /// accounts are rejected by every other instruction.
+    pub fn migrate_user(ctx: Context<MigrateUser>) -> Result<()> {
+        let info = ctx.accounts.user_stake.to_account_info();
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(
+                is_legacy_layout(&data, UserStake::DISCRIMINATOR, UserStake::LEN),
+                StakingError::AlreadyMigrated
+            );
+            LegacyUserStake::deserialize(&mut &data[8..])?
+        };
+        let pool = &mut ctx.accounts.pool;
+        require_keys_eq!(legacy.pool, pool.key(), StakingError::InvalidMigrationAccount);
+        let (user_stake_key, _) = Pubkey::find_program_address(
+            &[b"user", pool.key().as_ref(), legacy.owner.as_ref()],
+            &ID,
+        );
+        require_keys_eq!(info.key(), user_stake_key, StakingError::InvalidMigrationAccount);
+
+        let user_stake = UserStake::from(legacy);
+        for (level, level_staking) in user_stake.staking_by_level.iter().enumerate() {
+            // Rewards accrued before the upgrade are owed out of the reserve seeded by
+            // `migrate_pool`, which also holds them
+            pool.reward_reserve = pool.reward_reserve.saturating_sub(level_staking.rewards_accrued);
+            pool.rewards_owed = pool.rewards_owed
+                .checked_add(level_staking.rewards_accrued)
+                .ok_or(StakingError::MathOverflow)?;
+            if level_staking.amount_staked == 0 {
+                continue;
+            }
+            let level_stats = pool.level_stats_mut(level);
+            level_stats.total_staked = level_stats
+                .total_staked
+                .checked_add(level_staking.amount_staked)
+                .ok_or(StakingError::MathOverflow)?;
+            level_stats.active_stakers = level_stats
+                .active_stakers
+                .checked_add(1)
+                .ok_or(StakingError::MathOverflow)?;
+        }
+
+        let old_size = rewrite_account(
+            &info,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+            UserStake::LEN,
+            &user_stake,
+        )?;
+
+        emit!(AccountMigratedEvent {
+            account: user_stake_key,
+            old_size: old_size as u32,
+            new_size: UserStake::LEN as u32,
+        });
+        Ok(())
+    }
+}
+
+// Transfers `amount` into `vault` and returns what it actually received, which is less
+// than `amount` for Token-2022 mints with a transfer fee.
+fn transfer_into_vault<'info>(
+    cpi: CpiContext<'_, '_, '_, 'info, TransferChecked<'info>>,
+    vault: &mut InterfaceAccount<'info, TokenAccount>,
+    amount: u64,
+    decimals: u8,
+) -> Result<u64> {
+    let vault_before = vault.amount;
+    token_interface::transfer_checked(cpi, amount, decimals)?;
+    vault.reload()?;
+    vault.amount
+        .checked_sub(vault_before)
+        .ok_or_else(|| error!(StakingError::MathOverflow))
+}
+
+// Whether `data` is an account of the original, smaller layout. Pools and stakes are
+// allocated at their full `LEN`, so only accounts from before an upgrade are shorter.
+fn is_legacy_layout(data: &[u8], discriminator: &[u8], len: usize) -> bool {
+    data.len() >= 8 && data.len() < len && &data[..8] == discriminator
+}
+
+// Grows a program account to `new_size`, the payer topping up rent, and writes `value`
+// over its old contents. Returns the previous size.
+fn rewrite_account<'info, T: AccountSerialize>(
+    info: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system: &Program<'info, System>,
+    new_size: usize,
+    value: &T,
+) -> Result<usize> {
+    let old_size = info.data_len();
+    if new_size > old_size {
+        let additional_rent = Rent::get()?
+            .minimum_balance(new_size)
+            .saturating_sub(info.lamports());
+        if additional_rent > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    system.to_account_info(),
+                    system_program::Transfer {
+                        from: payer.to_account_info(),
+                        to: info.clone(),
+                    },
+                ),
+                additional_rent,
+            )?;
+        }
+        info.resize(new_size)?;
+    }
+
+    let mut data = info.try_borrow_mut_data()?;
+    data.fill(0);
+    value.try_serialize(&mut &mut data[..])?;
+    Ok(old_size)
+}
+
+// Validates level limits and writes them to the pool. Shared by `update_limits` and
+// governance proposals.
+fn apply_limits(
+    pool: &mut Account<Pool>,
+    authority: Pubkey,
+    staking_limits_by_level: Vec<StakingLimit>,
+) -> Result<()> {
+    require!(
+        staking_limits_by_level.len() <= MAX_LEVELS,
+        StakingError::TooManyLevels
+    );
+
+    for lvl in staking_limits_by_level.iter() {
+        require!(lvl.min <= lvl.max, StakingError::InvalidLimits);
+        require!(
+            lvl.apy_tiers.len() <= MAX_APY_TIERS,
+            StakingError::TooManyApyTiers
+        );
+        require!(
+            lvl.lock_options.len() <= MAX_LOCK_OPTIONS,
+            StakingError::TooManyLockOptions
+        );
+        // Tiers must be sorted by strictly increasing threshold
+        require!(
+            lvl.apy_tiers
+                .windows(2)
+                .all(|pair| pair[0].min_amount < pair[1].min_amount),
+            StakingError::InvalidLimits
+        );
+    }
+
+    // Live positions keep pointing at their level and lock option
+    for (level, current) in pool.staking_limits_by_level.iter().enumerate() {
+        let active = pool
+            .level_stats
+            .get(level)
+            .is_some_and(|stats| stats.active_stakers > 0);
+        if active {
+            require!(
+                staking_limits_by_level
+                    .get(level)
+                    .is_some_and(|new| new.lock_options.len() >= current.lock_options.len()),
+                StakingError::LimitsInUse
+            );
+        }
+    }
+
+    let levels_count = staking_limits_by_level.len() as u64;
+    pool.staking_limits_by_level = staking_limits_by_level;
+
+    emit!(UpdateLimitsEvent {
+        authority,
+        levels_count,
+        pool: pool.key(),
+    });
+
+    Ok(())
+}
+
+// Validates and records a stake of `amount` on `level` once the tokens are in the vault.
+fn stake_internal(
+    pool: &mut Account<Pool>,
+    user_stake: &mut Account<UserStake>,
+    external_state: &Account<UserAccount>,
+    referral_accounts: &[AccountInfo],
+    amount: u64,
+    level: u8,
+    lock_option: u8,
+) -> Result<()> {
+    require!(
+        level < pool.staking_limits_by_level.len() as u8,
+        StakingError::LevelOutOfRange
+    );
+
+    let level_limits = &pool.staking_limits_by_level[level as usize];
+    require!(
+        lock_option as usize <= level_limits.lock_options.len(),
+        StakingError::InvalidLockOption
+    );
+    let level_idx = level as usize;
+    let current_time = Clock::get()?.unix_timestamp as u64;
+
+    // Ensure staking_by_level vector is large enough
+    while user_stake.staking_by_level.len() <= level_idx {
+        user_stake.staking_by_level.push(StakingInfo::default());
+    }
+
+    let already_stake = user_stake.staking_by_level[level_idx].amount_staked;
+    let is_new_stake = already_stake == 0;
+    let used_cycles = user_stake.staking_by_level[level_idx].cycle_used;
+    let is_restake = is_new_stake && used_cycles > 0;
+    // End and grace period of the ended lock when topping up an unlocked position
+    let mut relock_after: Option<(u64, u64)> = None;
+
+    if is_new_stake {
+        // New stake or restake: requires cycle and must meet min limit
+        let cycles = get_x3_cycles(
+            external_state,
+            user_stake.owner,
+            level,
+        )?;
+
+        require!(
+            amount >= level_limits.min && amount <= level_limits.max,
+            StakingError::AmountOutOfLimits
+        );
+
+        require!(
+            (cycles as u64) > used_cycles,
+            StakingError::NoStakingRights
+        );
+        user_stake.staking_by_level[level_idx].cycle_used = used_cycles
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+        user_stake.staking_by_level[level_idx].lock_option = lock_option;
+
+        // Restaking within the grace window keeps the loyalty streak, a lapse resets it
+        if is_restake {
+            let level_staking = &mut user_stake.staking_by_level[level_idx];
+            level_staking.streak =
+                level_staking.restake_streak(level_limits.loyalty.grace_period, current_time)?;
+        }
+    } else {
+        let existing = &user_stake.staking_by_level[level_idx];
+        let period_end_time = existing
+            .start_staking_time
+            .checked_add(level_limits.period_for(existing.lock_option))
+            .ok_or(StakingError::MathOverflow)?;
+        let new_total = already_stake
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        if current_time < period_end_time {
+            // Adding to a running lock: same lock option and new total doesn't exceed max
+            require!(
+                existing.lock_option == lock_option,
+                StakingError::LockOptionMismatch
+            );
+            require!(
+                new_total <= level_limits.max,
+                StakingError::AmountOutOfLimits
+            );
+        } else {
+            // Topping up an unlocked remainder: the new total starts a fresh lock, like a
+            // relock from `partial_unstake`, without consuming a cycle right
+            require!(
+                existing.unbonding_start == 0,
+                StakingError::UnbondingAlreadyRequested
+            );
+            require!(
+                new_total >= level_limits.min && new_total <= level_limits.max,
+                StakingError::AmountOutOfLimits
+            );
+            relock_after = Some((period_end_time, level_limits.loyalty.grace_period));
+        }
+    }
+
+    if level_limits.cap > 0 {
+        let level_total = pool
+            .level_stats
+            .get(level_idx)
+            .map(|stats| stats.total_staked)
+            .unwrap_or_default()
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(level_total <= level_limits.cap, StakingError::LevelCapReached);
+    }
+    if pool.staking_cap > 0 {
+        let pool_total = pool
+            .total_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(
+            pool_total <= pool.staking_cap,
+            StakingError::PoolCapReached
+        );
+    }
+
+    if !is_new_stake {
+        let accrued = accrue_rewards_internal(
+            pool,
+            user_stake,
+            external_state,
+            referral_accounts,
+        )?;
+
+        // Emit accrual events
+        for reward in accrued {
+            emit!(AccrueRewardsEvent {
+                owner: user_stake.owner,
+                pool: pool.key(),
+                level: reward.level,
+                amount: reward.accrued_amount,
+                total_rewards: reward.total_rewards,
+                boost_rewards: reward.boost_rewards,
+                total_boost_rewards: reward.total_boost_rewards,
+            });
+        }
+    }
+
+    if let Some((period_end_time, grace_period)) = relock_after {
+        // Rewards of the ended lock are accrued above, before its terms change
+        let level_staking = &mut user_stake.staking_by_level[level_idx];
+        level_staking.last_period_end = period_end_time;
+        level_staking.streak = level_staking.restake_streak(grace_period, current_time)?;
+        level_staking.lock_option = lock_option;
+    }
+    if is_new_stake || relock_after.is_some() {
+        user_stake.staking_by_level[level_idx].start_staking_time = current_time;
+    }
+    user_stake.staking_by_level[level_idx].amount_staked = user_stake.staking_by_level[level_idx]
+        .amount_staked
+        .checked_add(amount)
+        .ok_or(StakingError::MathOverflow)?;
+    user_stake.staking_by_level[level_idx].last_update_ts = current_time;
+
+    pool.total_staked = pool
+        .total_staked
+        .checked_add(amount)
+        .ok_or(StakingError::MathOverflow)?;
+    let level_stats = pool.level_stats_mut(level_idx);
+    level_stats.total_staked = level_stats
+        .total_staked
+        .checked_add(amount)
+        .ok_or(StakingError::MathOverflow)?;
+    if is_new_stake {
+        level_stats.active_stakers = level_stats
+            .active_stakers
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+
+    // Emit appropriate event based on staking type
+    if is_restake || relock_after.is_some() {
+        emit!(RestakeEvent {
+            owner: user_stake.owner,
+            pool: pool.key(),
+            level,
+            amount,
+            streak: user_stake.staking_by_level[level_idx].streak,
+            lock_option,
+        });
+    } else if is_new_stake {
+        emit!(NewStakeEvent {
+            owner: user_stake.owner,
+            pool: pool.key(),
+            level,
+            amount,
+            lock_option,
+        });
+    } else {
+        let total_amount = user_stake.staking_by_level[level_idx].amount_staked;
+        emit!(AddToStakeEvent {
+            owner: user_stake.owner,
+            pool: pool.key(),
+            level,
+            amount,
+            total_amount,
+        });
+    }
+
+    Ok(())
+}
+
+fn accrue_rewards_internal(
+    pool: &mut Account<Pool>,
+    user: &mut Account<UserStake>,
+    external_account: &Account<UserAccount>,
+    referral_accounts: &[AccountInfo],
+) -> Result<Vec<AccruedReward>> {
+    // Returns Vec<AccruedReward> with reward information for each level
+    // Ensure wallet == user.owner
+    require_keys_eq!(external_account.wallet, user.owner, StakingError::InvalidExternalStateData);
+
+    // Convert boosters (closed_cycles) to Vec<(u64, u8)> format
+    let boosters: Vec<(u64, u8)> = external_account.closed_cycles
+        .iter()
+        .map(|cycle| (cycle.created_time, cycle.level))
+        .collect();
+
+    let now = Clock::get()?.unix_timestamp as u64;
+    let mut accrued_rewards: Vec<AccruedReward> = Vec::new();
+
+    // Accrual is capped by the reward reserve, keeping room for the referral shares paid on
+    // top. What the reserve cannot fund is forfeited.
+    let referral_bps: u64 = pool.referral_bps_by_depth.iter().sum();
+    let mut budget = u64::try_from(
+        (pool.reward_reserve as u128) * 10_000 / (10_000 + referral_bps as u128),
+    )
+    .map_err(|_| StakingError::MathOverflow)?;
+    let mut shortfall: u64 = 0;
+
+    // Process each level staking
+    for (level, level_staking) in user.staking_by_level.iter_mut().enumerate() {
+        // Check if staking period has ended (start_staking_time + period > now)
+        let level_limits = if level < pool.staking_limits_by_level.len() {
+            &pool.staking_limits_by_level[level]
+        } else {
+            continue; // Skip if no limits defined for this level
+        };
+
+        if level_staking.amount_staked == 0 || level_staking.unbonding_start != 0 {
+            continue;
+        }
+
+        let period_end_time = level_staking
+            .start_staking_time
+            .checked_add(level_limits.period_for(level_staking.lock_option))
+            .ok_or(StakingError::MathOverflow)?;
+        let reward_start_time = level_staking.last_update_ts;
+
+        if period_end_time <= reward_start_time {
+            continue; // Period has ended, no more rewards
+        }
+
+        // Calculate time delta from max(last_update_ts, close_level_time)
+        if now <= reward_start_time {
+            continue;
+        }
 
         let reward_end_time = now.min(period_end_time);
 
         let dt: i128 = (reward_end_time as i128) - (reward_start_time as i128);
 
-        // Use APY from stake limits for this level
-        let apy_bps = level_limits.apy_bps as i128;
+        // Use APY from stake limits for this level, picking the tier for the current stake
+        // and applying the loyalty bonus for consecutive restakes
+        let base_apy_bps = level_limits
+            .apy_for(level_staking.amount_staked, level_staking.lock_option) as i128;
+        let loyalty_bps = level_limits.loyalty.bonus_for(level_staking.streak) as i128;
+        let apy_bps = base_apy_bps
+            .checked_mul(10_000 + loyalty_bps)
+            .ok_or(StakingError::MathOverflow)?
+            / 10_000;
 
         // Rewards = stake * (apr_bps/10_000) * (dt/seconds_per_year)
         let stake: i128 = level_staking.amount_staked as i128;
@@ -446,6 +1729,7 @@ fn accrue_rewards_internal(
 
         if reward_i128 > 0 {
             let reward_u64: u64 = u64::try_from(reward_i128).map_err(|_| StakingError::MathOverflow)?;
+            let reward_u64 = draw_from_budget(&mut budget, &mut shortfall, reward_u64);
             level_accrued = reward_u64;
             level_staking.rewards_accrued = level_staking
                 .rewards_accrued
@@ -453,17 +1737,18 @@ fn accrue_rewards_internal(
                 .ok_or(StakingError::MathOverflow)?;
         }
 
+        let boost_by_cycle = level_limits.boost_for(level_staking.lock_option);
         for boost in boosters.iter().filter(|boost| boost.1 as usize == level) {
             // Compute boost interval within [reward_start_time, reward_end_time]
             let boost_period_end = boost
                 .0
-                .checked_add(level_limits.boost_by_cycle.period)
+                .checked_add(boost_by_cycle.period)
                 .unwrap_or(u64::MAX);
             let boost_end = reward_end_time.min(boost_period_end);
             let boost_start = boost.0.max(reward_start_time);
             if boost_end > boost_start  {
                 // Additional reward using boost APY only for the boost interval.
-                let boost_apy_bps = level_limits.boost_by_cycle.apy as i128;
+                let boost_apy_bps = boost_by_cycle.apy as i128;
                 let dt = boost_end as i128 - boost_start as i128;
                 let numer_boost = stake
                     .checked_mul(boost_apy_bps)
@@ -474,6 +1759,7 @@ fn accrue_rewards_internal(
                 if reward_boost_i128 > 0 {
                     let reward_u64: u64 = u64::try_from(reward_boost_i128)
                         .map_err(|_| StakingError::MathOverflow)?;
+                    let reward_u64 = draw_from_budget(&mut budget, &mut shortfall, reward_u64);
                     level_boost_accrued = level_boost_accrued
                         .checked_add(reward_u64)
                         .ok_or(StakingError::MathOverflow)?;
@@ -506,9 +1792,189 @@ fn accrue_rewards_internal(
         }
     }
 
+    let mut total_accrued: u64 = 0;
+    for reward in accrued_rewards.iter() {
+        let level_stats = pool.level_stats_mut(reward.level as usize);
+        level_stats.rewards_accrued = level_stats
+            .rewards_accrued
+            .checked_add(reward.accrued_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        total_accrued = total_accrued
+            .checked_add(reward.accrued_amount)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+
+    if total_accrued > 0 {
+        let referral_paid =
+            distribute_referral_rewards(pool, external_account, total_accrued, referral_accounts)?;
+        pool.record_accrual(
+            total_accrued
+                .checked_add(referral_paid)
+                .ok_or(StakingError::MathOverflow)?,
+        )?;
+    }
+    if shortfall > 0 {
+        emit!(RewardShortfallEvent {
+            pool: pool.key(),
+            owner: user.owner,
+            amount: shortfall,
+        });
+    }
+
     Ok(accrued_rewards)
 }
 
+// Takes up to `amount` from the remaining reward budget, adding what is missing to `shortfall`.
+fn draw_from_budget(budget: &mut u64, shortfall: &mut u64, amount: u64) -> u64 {
+    let drawn = amount.min(*budget);
+    *budget -= drawn;
+    *shortfall = shortfall.saturating_add(amount - drawn);
+    drawn
+}
+
+// Credits each upline's `ReferralRewards` with its share of `accrued`, walking the x3
+// referrer chain up to `referral_bps_by_depth.len()` levels. Every upline's rewards account
+// and x3 account must be supplied; an upline whose rewards account was never created is
+// skipped, and the walk stops at an upline with no x3 account. Skipped shares stay in the
+// reward reserve.
+// Returns the total credited.
+fn distribute_referral_rewards(
+    pool: &Account<Pool>,
+    external_account: &UserAccount,
+    accrued: u64,
+    referral_accounts: &[AccountInfo],
+) -> Result<u64> {
+    let depth_count = pool.referral_bps_by_depth.len();
+    let mut total_paid: u64 = 0;
+    let mut current_wallet = external_account.referrer;
+
+    for (depth, bps) in pool.referral_bps_by_depth.iter().enumerate() {
+        if current_wallet == Pubkey::default() {
+            break;
+        }
+
+        let share = u64::try_from((accrued as u128) * (*bps as u128) / 10_000)
+            .map_err(|_| StakingError::MathOverflow)?;
+        let rewards_key = Pubkey::find_program_address(
+            &[b"referral", pool.key().as_ref(), current_wallet.as_ref()],
+            &ID,
+        )
+        .0;
+        let rewards_info = referral_accounts
+            .iter()
+            .find(|info| info.key == &rewards_key)
+            .ok_or(StakingError::MissingReferralAccount)?;
+
+        if share > 0 && rewards_info.owner == &ID && !rewards_info.data_is_empty() {
+            let mut referral_rewards = {
+                let mut data: &[u8] = &rewards_info.try_borrow_data()?;
+                ReferralRewards::try_deserialize(&mut data)?
+            };
+            referral_rewards.claimable = referral_rewards
+                .claimable
+                .checked_add(share)
+                .ok_or(StakingError::MathOverflow)?;
+            referral_rewards.total_earned = referral_rewards
+                .total_earned
+                .checked_add(share)
+                .ok_or(StakingError::MathOverflow)?;
+            let mut dst = rewards_info.try_borrow_mut_data()?;
+            referral_rewards.try_serialize(&mut *dst)?;
+            total_paid = total_paid
+                .checked_add(share)
+                .ok_or(StakingError::MathOverflow)?;
+
+            emit!(ReferralRewardEvent {
+                pool: pool.key(),
+                upline: current_wallet,
+                from: external_account.wallet,
+                depth: depth as u8 + 1,
+                amount: share,
+            });
+        }
+
+        if depth + 1 == depth_count {
+            break;
+        }
+
+        // Move up to the next referrer through the upline's x3 account
+        let upline_key = pool.x3_user_key(&current_wallet);
+        let upline_info = referral_accounts
+            .iter()
+            .find(|info| info.key == &upline_key)
+            .ok_or(StakingError::MissingReferralAccount)?;
+        if upline_info.data_is_empty() {
+            break;
+        }
+        require_keys_eq!(
+            *upline_info.owner,
+            x3_pepe::ID,
+            StakingError::InvalidExternalStateOwner
+        );
+        let upline = {
+            let mut data: &[u8] = &upline_info.try_borrow_data()?;
+            UserAccount::try_deserialize(&mut data)?
+        };
+        current_wallet = upline.referrer;
+    }
+
+    Ok(total_paid)
+}
+
+// Records the owner's current voting weight: staked principal on every level not
+// being unbonded, weighted up by the lock time left (1x plus 1x per remaining year of lock,
+// up to MAX_VOTE_LOCK).
+fn checkpoint_voting_weight(
+    ledger: &mut Account<VoteLedger>,
+    bump: u8,
+    pool: &Account<Pool>,
+    user: &Account<UserStake>,
+) -> Result<()> {
+    if ledger.owner == Pubkey::default() {
+        ledger.pool = pool.key();
+        ledger.owner = user.owner;
+        ledger.bump = bump;
+    }
+
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp as u64;
+    let mut weight: u64 = 0;
+    for (level, level_staking) in user.staking_by_level.iter().enumerate() {
+        if level_staking.amount_staked == 0 || level_staking.unbonding_start != 0 {
+            continue;
+        }
+        // Only the part of the lock still ahead counts, an expired lock earns no bonus
+        let lock = pool
+            .staking_limits_by_level
+            .get(level)
+            .map(|limits| {
+                level_staking
+                    .start_staking_time
+                    .saturating_add(limits.period_for(level_staking.lock_option))
+                    .saturating_sub(now)
+                    .min(MAX_VOTE_LOCK)
+            })
+            .unwrap_or_default() as u128;
+        let amount = level_staking.amount_staked as u128;
+        let bonus = amount * lock / SECONDS_PER_YEAR as u128;
+        let level_weight = u64::try_from(amount + bonus).map_err(|_| StakingError::MathOverflow)?;
+        weight = weight
+            .checked_add(level_weight)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+
+    ledger.record(clock.slot, now, weight);
+
+    emit!(VotingWeightEvent {
+        owner: ledger.owner,
+        pool: ledger.pool,
+        slot: clock.slot,
+        weight,
+    });
+
+    Ok(())
+}
+
 fn get_x3_cycles(
     external_account: &Account<UserAccount>,
     expected_owner: Pubkey,
@@ -524,13 +1990,153 @@ fn get_x3_cycles(
     Ok(external_account.matrix[required_level as usize].cycles)
 }
 
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub enum ProposalAction {
+    UpdateLimits { staking_limits_by_level: Vec<StakingLimit> },
+    UpdatePrices { prices: Vec<u64> },
+}
+impl ProposalAction {
+    // Largest variant: discriminator + vec length + limits for every level.
+    pub const MAX_SIZE: usize = 1 + 4 + MAX_LEVELS * StakingLimit::MAX_SIZE;
+}
+
+#[account]
+pub struct Governance {
+    pub pool: Pubkey,
+    // Seconds a proposal is open for voting.
+    pub voting_period: u64,
+    // Seconds between the end of voting and execution.
+    pub timelock: u64,
+    // Minimum weight voting for a proposal to pass.
+    pub quorum: u64,
+    // Minimum weight needed to open a proposal.
+    pub proposal_threshold: u64,
+    pub proposal_count: u64,
+    pub bump: u8,
+}
+impl Governance {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 8 + 1;
+}
+
+#[account]
+pub struct Proposal {
+    pub governance: Pubkey,
+    pub id: u64,
+    pub proposer: Pubkey,
+    pub action: ProposalAction,
+    pub snapshot_slot: u64,
+    pub voting_ends_at: u64,
+    pub votes_for: u64,
+    pub votes_against: u64,
+    pub executed: bool,
+    pub bump: u8,
+}
+impl Proposal {
+    pub const LEN: usize =
+        8 + 32 + 8 + 32 + ProposalAction::MAX_SIZE + 8 + 8 + 8 + 8 + 1 + 1;
+}
+
+#[account]
+pub struct VoteRecord {
+    pub proposal: Pubkey,
+    pub voter: Pubkey,
+    pub support: bool,
+    pub weight: u64,
+}
+impl VoteRecord {
+    pub const LEN: usize = 8 + 32 + 32 + 1 + 8;
+}
+
 #[derive(Clone, AnchorDeserialize, AnchorSerialize)]
 pub struct StakingLimit {
     pub min: u64,
     pub max: u64,
     pub period: u64,
     pub apy_bps: u64,
-    pub boost_by_cycle: Boost
+    pub boost_by_cycle: Boost,
+    // Cooldown between `request_unstake` and `withdraw`, 0 to unstake directly.
+    pub unbonding_period: u64,
+    // Maximum total principal staked on this level, 0 for no cap.
+    pub cap: u64,
+    // Higher rates for larger positions, sorted by `min_amount`.
+    pub apy_tiers: Vec<ApyTier>,
+    pub loyalty: Loyalty,
+    // Extra lock durations a staker can pick instead of `period`.
+    pub lock_options: Vec<LockOption>,
+}
+
+impl StakingLimit {
+    pub const MAX_SIZE: usize =
+        8 + 8 + 8 + 8 + (8 + 8) + 8 + 8
+            + 4 + MAX_APY_TIERS * (8 + 8)
+            + (8 + 8 + 8)
+            + 4 + MAX_LOCK_OPTIONS * (8 + 8 + (8 + 8));
+
+    // Lock option `n` is `lock_options[n - 1]`, 0 is the level's own terms.
+    fn extra_lock(&self, lock_option: u8) -> Option<&LockOption> {
+        (lock_option as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.lock_options.get(idx))
+    }
+
+    pub fn period_for(&self, lock_option: u8) -> u64 {
+        self.extra_lock(lock_option)
+            .map_or(self.period, |option| option.period)
+    }
+
+    pub fn boost_for(&self, lock_option: u8) -> &Boost {
+        self.extra_lock(lock_option)
+            .map_or(&self.boost_by_cycle, |option| &option.boost_by_cycle)
+    }
+
+    // APY for a position of `amount_staked`: the highest tier it reaches, else `apy_bps`.
+    // A chosen lock option replaces `apy_bps` with its own rate and keeps the tier's
+    // premium over `apy_bps` on top.
+    pub fn apy_for(&self, amount_staked: u64, lock_option: u8) -> u64 {
+        let tier_apy = self
+            .apy_tiers
+            .iter()
+            .rev()
+            .find(|tier| amount_staked >= tier.min_amount)
+            .map(|tier| tier.apy_bps)
+            .unwrap_or(self.apy_bps);
+        match self.extra_lock(lock_option) {
+            Some(option) => option
+                .apy_bps
+                .saturating_add(tier_apy.saturating_sub(self.apy_bps)),
+            None => tier_apy,
+        }
+    }
+}
+
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct LockOption {
+    pub period: u64,
+    pub apy_bps: u64,
+    pub boost_by_cycle: Boost,
+}
+
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct ApyTier {
+    pub min_amount: u64,
+    pub apy_bps: u64,
+}
+
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct Loyalty {
+    // Relative APY bonus per consecutive restake, in bps of the base rate.
+    pub bonus_bps_per_restake: u64,
+    pub max_bonus_bps: u64,
+    // Time after the previous period ends in which a restake keeps the streak.
+    pub grace_period: u64,
+}
+
+impl Loyalty {
+    pub fn bonus_for(&self, streak: u64) -> u64 {
+        streak
+            .saturating_mul(self.bonus_bps_per_restake)
+            .min(self.max_bonus_bps)
+    }
 }
 
 #[account]
@@ -541,13 +2147,104 @@ pub struct Pool {
     pub total_staked: u64,
     pub staking_limits_by_level: Vec<StakingLimit>,
     pub bump: u8,
+    // Maximum total principal across all levels, 0 for no cap.
+    pub staking_cap: u64,
+    pub level_stats: Vec<LevelStats>,
+    // Share of accrued rewards paid to each upline depth, in bps.
+    pub referral_bps_by_depth: Vec<u64>,
+    // Reward budget in the vault not yet accrued to anyone.
+    pub reward_reserve: u64,
+    // Rewards accrued to stakers and uplines but not paid out yet.
+    pub rewards_owed: u64,
+    // x3 game whose user accounts gate staking, fixed at initialization.
+    pub x3_game_id: u64,
 }
 impl Pool {
     pub const LEN: usize =
-        8 + 32 + 32 + 32 + 8 + 1 + 8 + MAX_LEVELS * (8 + 8 + 8 + 8 + 8 + 8 + 8);
+        8 + 32 + 32 + 32 + 8 + 1 + 8 + MAX_LEVELS * StakingLimit::MAX_SIZE
+            + 8 + 4 + MAX_LEVELS * (8 + 8 + 8 + 8 + 8)
+            + 4 + MAX_REFERRAL_DEPTH * 8
+            + 8 + 8
+            + 8;
+
+    pub fn x3_game_seed(&self) -> Vec<u8> {
+        x3_pepe::game_seed(self.x3_game_id)
+    }
+
+    // Address of `wallet`'s x3 user account in the pool's game.
+    pub fn x3_user_key(&self, wallet: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(
+            &[b"user", self.x3_game_seed().as_ref(), wallet.as_ref()],
+            &x3_pepe::ID,
+        )
+        .0
+    }
+
+    pub fn level_stats_mut(&mut self, level: usize) -> &mut LevelStats {
+        while self.level_stats.len() <= level {
+            self.level_stats.push(LevelStats {
+                total_staked: 0,
+                active_stakers: 0,
+                rewards_accrued: 0,
+                rewards_paid: 0,
+                boost_rewards_paid: 0,
+            });
+        }
+        &mut self.level_stats[level]
+    }
+
+    // Updates level totals for a payout. Positions from before per-level tracking are
+    // counted by `migrate_user`; the totals still saturate so a payout never fails on them.
+    fn record_unstake(
+        &mut self,
+        level: usize,
+        amount: u64,
+        rewards: u64,
+        boost_rewards: u64,
+        closes_position: bool,
+    ) -> Result<()> {
+        let level_stats = self.level_stats_mut(level);
+        level_stats.total_staked = level_stats.total_staked.saturating_sub(amount);
+        if closes_position {
+            level_stats.active_stakers = level_stats.active_stakers.saturating_sub(1);
+        }
+        level_stats.rewards_paid = level_stats
+            .rewards_paid
+            .checked_add(rewards)
+            .ok_or(StakingError::MathOverflow)?;
+        level_stats.boost_rewards_paid = level_stats
+            .boost_rewards_paid
+            .checked_add(boost_rewards)
+            .ok_or(StakingError::MathOverflow)?;
+        self.rewards_owed = self.rewards_owed.saturating_sub(rewards);
+        Ok(())
+    }
+
+    // Moves newly accrued rewards from the reserve to what is owed. Accrual is capped by
+    // the reserve, so it always covers `amount`.
+    fn record_accrual(&mut self, amount: u64) -> Result<()> {
+        self.reward_reserve = self.reward_reserve
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        self.rewards_owed = self.rewards_owed
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        Ok(())
+    }
 }
 
 #[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct LevelStats {
+    pub total_staked: u64,
+    pub active_stakers: u64,
+    // Rewards accrued on this level, including boost rewards.
+    pub rewards_accrued: u64,
+    // Rewards paid out on this level, including boost rewards.
+    pub rewards_paid: u64,
+    pub boost_rewards_paid: u64,
+}
+
+#[derive(Clone, Default, AnchorDeserialize, AnchorSerialize)]
 pub struct StakingInfo {
     pub amount_staked: u64,
     pub rewards_accrued: u64,
@@ -555,6 +2252,35 @@ pub struct StakingInfo {
     pub start_staking_time: u64,
     pub boost_rewards_total: u64,
     pub cycle_used: u64,
+    // Time `request_unstake` was called, 0 if no withdrawal is pending.
+    pub unbonding_start: u64,
+    // Part of `boost_rewards_total` already paid out.
+    pub boost_rewards_paid: u64,
+    // Consecutive restakes within the loyalty grace window.
+    pub streak: u64,
+    // End of the previous lock, used to check the loyalty grace window.
+    pub last_period_end: u64,
+    // Lock option chosen at stake time, see `StakingLimit::period_for`.
+    pub lock_option: u8,
+}
+
+impl StakingInfo {
+    // Marks boost rewards accrued since the last payout as paid and returns them.
+    fn take_unpaid_boost(&mut self) -> u64 {
+        let unpaid = self.boost_rewards_total.saturating_sub(self.boost_rewards_paid);
+        self.boost_rewards_paid = self.boost_rewards_total;
+        unpaid
+    }
+
+    // Streak for a new lock starting at `now`: restaking within the grace window after
+    // `last_period_end` extends it, a lapse resets it.
+    fn restake_streak(&self, grace_period: u64, now: u64) -> Result<u64> {
+        if now <= self.last_period_end.saturating_add(grace_period) {
+            Ok(self.streak.checked_add(1).ok_or(StakingError::MathOverflow)?)
+        } else {
+            Ok(0)
+        }
+    }
 }
 
 #[derive(Clone, AnchorDeserialize, AnchorSerialize)]
@@ -580,7 +2306,184 @@ pub struct UserStake {
 }
 impl UserStake {
     pub const LEN: usize =
-        8 + 32 + 32 + (8 + 8 + 8 + 8 + 8 + 8) * MAX_LEVELS;
+        8 + 32 + 32 + 4 + (8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 1) * MAX_LEVELS;
+}
+
+/// `Pool` as written by the original program, before level stats, caps and referral rewards.
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct LegacyPool {
+    pub authority: Pubkey,
+    pub initializer: Pubkey,
+    pub staking_mint: Pubkey,
+    pub total_staked: u64,
+    pub staking_limits_by_level: Vec<LegacyStakingLimit>,
+    pub bump: u8,
+}
+
+/// `StakingLimit` before unbonding, caps, APY tiers, loyalty and lock options.
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct LegacyStakingLimit {
+    pub min: u64,
+    pub max: u64,
+    pub period: u64,
+    pub apy_bps: u64,
+    pub boost_by_cycle: Boost,
+}
+
+/// `UserStake` as written by the original program.
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct LegacyUserStake {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub staking_by_level: Vec<LegacyStakingInfo>,
+}
+
+/// `StakingInfo` before unbonding, loyalty and lock options.
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct LegacyStakingInfo {
+    pub amount_staked: u64,
+    pub rewards_accrued: u64,
+    pub last_update_ts: u64,
+    pub start_staking_time: u64,
+    pub boost_rewards_total: u64,
+    pub cycle_used: u64,
+}
+
+impl From<LegacyPool> for Pool {
+    fn from(legacy: LegacyPool) -> Self {
+        Pool {
+            authority: legacy.authority,
+            initializer: legacy.initializer,
+            staking_mint: legacy.staking_mint,
+            total_staked: legacy.total_staked,
+            staking_limits_by_level: legacy
+                .staking_limits_by_level
+                .into_iter()
+                .map(|limits| StakingLimit {
+                    min: limits.min,
+                    max: limits.max,
+                    period: limits.period,
+                    apy_bps: limits.apy_bps,
+                    boost_by_cycle: limits.boost_by_cycle,
+                    unbonding_period: 0,
+                    cap: 0,
+                    apy_tiers: Vec::new(),
+                    loyalty: Loyalty {
+                        bonus_bps_per_restake: 0,
+                        max_bonus_bps: 0,
+                        grace_period: 0,
+                    },
+                    lock_options: Vec::new(),
+                })
+                .collect(),
+            bump: legacy.bump,
+            staking_cap: 0,
+            // Filled in as `migrate_user` counts each position
+            level_stats: Vec::new(),
+            referral_bps_by_depth: Vec::new(),
+            reward_reserve: 0,
+            rewards_owed: 0,
+            // The original program only read game 0 accounts
+            x3_game_id: 0,
+        }
+    }
+}
+
+impl From<LegacyUserStake> for UserStake {
+    fn from(legacy: LegacyUserStake) -> Self {
+        UserStake {
+            pool: legacy.pool,
+            owner: legacy.owner,
+            staking_by_level: legacy
+                .staking_by_level
+                .into_iter()
+                .map(|info| StakingInfo {
+                    amount_staked: info.amount_staked,
+                    rewards_accrued: info.rewards_accrued,
+                    last_update_ts: info.last_update_ts,
+                    start_staking_time: info.start_staking_time,
+                    boost_rewards_total: info.boost_rewards_total,
+                    cycle_used: info.cycle_used,
+                    // Boost paid before the migration is not tracked in level stats
+                    boost_rewards_paid: info.boost_rewards_total,
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+}
+
+#[account]
+pub struct ReferralRewards {
+    pub pool: Pubkey,
+    pub wallet: Pubkey,
+    pub claimable: u64,
+    pub total_earned: u64,
+    pub bump: u8,
+}
+impl ReferralRewards {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 8 + 1;
+}
+
+#[account]
+pub struct VoteLedger {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub bump: u8,
+    // Set once old checkpoints have been dropped to stay within MAX_CHECKPOINTS.
+    pub pruned: bool,
+    pub checkpoints: Vec<Checkpoint>,
+}
+impl VoteLedger {
+    pub const LEN: usize =
+        8 + 32 + 32 + 1 + 1 + 4 + MAX_CHECKPOINTS * (8 + 8 + 8);
+
+    /// Records `weight` for `slot`, replacing a checkpoint already taken in the same slot and
+    /// dropping the oldest one once MAX_CHECKPOINTS is reached.
+    pub fn record(&mut self, slot: u64, timestamp: u64, weight: u64) {
+        match self.checkpoints.last_mut() {
+            Some(last) if last.slot == slot => {
+                last.weight = weight;
+            }
+            _ => {
+                if self.checkpoints.len() >= MAX_CHECKPOINTS {
+                    self.checkpoints.remove(0);
+                    self.pruned = true;
+                }
+                self.checkpoints.push(Checkpoint {
+                    slot,
+                    timestamp,
+                    weight,
+                });
+            }
+        }
+    }
+
+    pub fn weight_at(&self, slot: u64) -> Result<u64> {
+        let idx = self.checkpoints.partition_point(|checkpoint| checkpoint.slot <= slot);
+        self.weight_before(idx)
+    }
+
+    pub fn weight_at_time(&self, timestamp: u64) -> Result<u64> {
+        let idx = self.checkpoints.partition_point(|checkpoint| checkpoint.timestamp <= timestamp);
+        self.weight_before(idx)
+    }
+
+    fn weight_before(&self, idx: usize) -> Result<u64> {
+        if idx == 0 {
+            // Before the first checkpoint the weight is 0, unless that history was pruned
+            require!(!self.pruned, StakingError::CheckpointUnavailable);
+            return Ok(0);
+        }
+        Ok(self.checkpoints[idx - 1].weight)
+    }
+}
+
+#[derive(Clone, AnchorDeserialize, AnchorSerialize)]
+pub struct Checkpoint {
+    pub slot: u64,
+    pub timestamp: u64,
+    pub weight: u64,
 }
 
 #[derive(Accounts)]
@@ -589,7 +2492,7 @@ pub struct InitializePool<'info> {
     #[account(mut)]
     pub authority: Signer<'info>,
 
-    pub staking_mint: Account<'info, Mint>,
+    pub staking_mint: InterfaceAccount<'info, Mint>,
 
     #[account(
         init,
@@ -605,141 +2508,485 @@ pub struct InitializePool<'info> {
         payer = authority,
         associated_token::mint = staking_mint,
         associated_token::authority = pool,
+        associated_token::token_program = token_program,
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct DepositRewards<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        associated_token::mint = pool.staking_mint,
+        associated_token::authority = authority,
+        associated_token::token_program = token_program
+    )]
+    pub from_authority_ata: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = pool.staking_mint,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SyncRewards<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        associated_token::mint = pool.staking_mint,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateLimits<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        has_one = authority,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct InitReferralRewards<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = payer,
+        space = ReferralRewards::LEN,
+        seeds = [b"referral", pool.key().as_ref(), wallet.as_ref()],
+        bump
+    )]
+    pub referral_rewards: Account<'info, ReferralRewards>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralRewards<'info> {
+    pub wallet: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        mut,
+        has_one = pool,
+        has_one = wallet,
+        seeds = [b"referral", pool.key().as_ref(), wallet.key().as_ref()],
+        bump = referral_rewards.bump
+    )]
+    pub referral_rewards: Account<'info, ReferralRewards>,
+    #[account(
+        mut,
+        associated_token::mint = pool.staking_mint,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = pool.staking_mint,
+        associated_token::authority = wallet,
+        associated_token::token_program = token_program
+    )]
+    pub to_wallet_ata: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = pool.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitGovernance<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        has_one = authority @ StakingError::Unauthorized,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = authority,
+        space = Governance::LEN,
+        seeds = [b"governance", pool.key().as_ref()],
+        bump
+    )]
+    pub governance: Account<'info, Governance>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateProposal<'info> {
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"governance", governance.pool.as_ref()],
+        bump = governance.bump
+    )]
+    pub governance: Account<'info, Governance>,
+    #[account(
+        seeds = [b"votes", governance.pool.as_ref(), proposer.key().as_ref()],
+        bump = vote_ledger.bump
+    )]
+    pub vote_ledger: Account<'info, VoteLedger>,
+    #[account(
+        init,
+        payer = proposer,
+        space = Proposal::LEN,
+        seeds = [b"proposal", governance.key().as_ref(), governance.proposal_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CastVote<'info> {
+    #[account(mut)]
+    pub voter: Signer<'info>,
+    #[account(
+        seeds = [b"governance", governance.pool.as_ref()],
+        bump = governance.bump
+    )]
+    pub governance: Account<'info, Governance>,
+    #[account(
+        mut,
+        has_one = governance,
+        seeds = [b"proposal", governance.key().as_ref(), proposal.id.to_le_bytes().as_ref()],
+        bump = proposal.bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+    #[account(
+        seeds = [b"votes", governance.pool.as_ref(), voter.key().as_ref()],
+        bump = vote_ledger.bump
+    )]
+    pub vote_ledger: Account<'info, VoteLedger>,
+    #[account(
+        init,
+        payer = voter,
+        space = VoteRecord::LEN,
+        seeds = [b"vote", proposal.key().as_ref(), voter.key().as_ref()],
+        bump
+    )]
+    pub vote_record: Account<'info, VoteRecord>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteProposal<'info> {
+    #[account(
+        mut,
+        seeds = [b"governance", governance.pool.as_ref()],
+        bump = governance.bump
+    )]
+    pub governance: Account<'info, Governance>,
+    #[account(
+        mut,
+        has_one = governance,
+        seeds = [b"proposal", governance.key().as_ref(), proposal.id.to_le_bytes().as_ref()],
+        bump = proposal.bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+    #[account(
+        mut,
+        address = governance.pool,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Validated by x3 `update_prices`.
+    #[account(mut)]
+    pub x3_global_state: Option<UncheckedAccount<'info>>,
+    /// CHECK: Validated by x3 `update_prices`.
+    #[account(mut)]
+    pub x3_root_account: Option<UncheckedAccount<'info>>,
+    pub x3_program: Option<Program<'info, X3Pepe>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAuthority<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        has_one = authority @ StakingError::Unauthorized,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct InitUser<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = UserStake::LEN,
+        seeds = [b"user", pool.key().as_ref(), owner.key().as_ref()],
+        bump
     )]
-    pub stake_vault: Account<'info, TokenAccount>,
+    pub user_stake: Account<'info, UserStake>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct DepositRewards<'info> {
+pub struct Stake<'info> {
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub owner: Signer<'info>,
+
     #[account(
         mut,
-        has_one = authority,
         seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
-        bump = pool.bump
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
     )]
     pub pool: Account<'info, Pool>,
 
+    #[account(
+        mut,
+        seeds = [b"user", pool.key().as_ref(), owner.key().as_ref()],
+        bump,
+        constraint = user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
     #[account(
         mut,
         associated_token::mint = pool.staking_mint,
-        associated_token::authority = authority
+        associated_token::authority = owner,
+        associated_token::token_program = token_program
     )]
-    pub from_authority_ata: Account<'info, TokenAccount>,
+    pub from_user_ata: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         mut,
         associated_token::mint = pool.staking_mint,
-        associated_token::authority = pool
+        associated_token::authority = pool,
+        associated_token::token_program = token_program
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), owner.key().as_ref()],
+        bump = external_state.bump,
+        seeds::program = x3_pepe::ID
+    )]
+    pub external_state: Account<'info, UserAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = VoteLedger::LEN,
+        seeds = [b"votes", pool.key().as_ref(), owner.key().as_ref()],
+        bump
     )]
-    pub stake_vault: Account<'info, TokenAccount>,
+    pub vote_ledger: Account<'info, VoteLedger>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(address = pool.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateLimits<'info> {
+pub struct StakeFromX3<'info> {
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub owner: Signer<'info>,
+
     #[account(
         mut,
-        has_one = authority,
         seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
-        bump = pool.bump
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
     )]
     pub pool: Account<'info, Pool>,
-}
 
-#[derive(Accounts)]
-pub struct UpdateAuthority<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
     #[account(
         mut,
-        has_one = authority @ StakingError::Unauthorized
+        seeds = [b"user", pool.key().as_ref(), owner.key().as_ref()],
+        bump,
+        constraint = user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
     )]
-    pub pool: Account<'info, Pool>,
-}
+    pub user_stake: Account<'info, UserStake>,
 
-#[derive(Accounts)]
-pub struct InitUser<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        associated_token::mint = pool.staking_mint,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program
+    )]
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
-        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
-        bump = pool.bump
+        mut,
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), owner.key().as_ref()],
+        bump = external_state.bump,
+        seeds::program = x3_pepe::ID
     )]
-    pub pool: Account<'info, Pool>,
+    pub external_state: Account<'info, UserAccount>,
 
     #[account(
-        init,
+        init_if_needed,
         payer = owner,
-        space = UserStake::LEN,
-        seeds = [b"user", pool.key().as_ref(), owner.key().as_ref()],
+        space = VoteLedger::LEN,
+        seeds = [b"votes", pool.key().as_ref(), owner.key().as_ref()],
         bump
     )]
-    pub user_stake: Account<'info, UserStake>,
+    pub vote_ledger: Account<'info, VoteLedger>,
+
+    /// CHECK: Validated by x3 `claim_to`.
+    pub x3_global_state: UncheckedAccount<'info>,
 
+    #[account(mut)]
+    pub x3_vault_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub x3_program: Program<'info, X3Pepe>,
+    #[account(address = pool.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Stake<'info> {
+pub struct Unstake<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
 
     #[account(
         mut,
         seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
-        bump = pool.bump
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
     )]
     pub pool: Account<'info, Pool>,
 
     #[account(
         mut,
-        seeds = [b"user", pool.key().as_ref(), owner.key().as_ref()],
-        bump
+        seeds = [b"user", pool.key().as_ref(), user_stake.owner.as_ref()],
+        bump,
+        has_one = owner,
+        constraint = user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
     )]
     pub user_stake: Account<'info, UserStake>,
 
     #[account(
         mut,
         associated_token::mint = pool.staking_mint,
-        associated_token::authority = owner
+        associated_token::authority = pool,
+        associated_token::token_program = token_program
     )]
-    pub from_user_ata: Account<'info, TokenAccount>,
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         mut,
         associated_token::mint = pool.staking_mint,
-        associated_token::authority = pool
+        associated_token::authority = owner,
+        associated_token::token_program = token_program
     )]
-    pub stake_vault: Account<'info, TokenAccount>,
+    pub to_user_stake_ata: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
-        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), user_stake.owner.as_ref()],
+        bump = external_state.bump,
+        seeds::program = x3_pepe::ID
     )]
     pub external_state: Account<'info, UserAccount>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = VoteLedger::LEN,
+        seeds = [b"votes", pool.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub vote_ledger: Account<'info, VoteLedger>,
+
+    #[account(address = pool.staking_mint)]
+    pub staking_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Unstake<'info> {
+pub struct RequestUnstake<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
 
     #[account(
         mut,
         seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
-        bump = pool.bump
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
     )]
     pub pool: Account<'info, Pool>,
 
@@ -747,53 +2994,205 @@ pub struct Unstake<'info> {
         mut,
         seeds = [b"user", pool.key().as_ref(), user_stake.owner.as_ref()],
         bump,
-        has_one = owner
+        has_one = owner,
+        constraint = user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
     )]
     pub user_stake: Account<'info, UserStake>,
 
     #[account(
-        mut,
-        associated_token::mint = pool.staking_mint,
-        associated_token::authority = pool
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), user_stake.owner.as_ref()],
+        bump = external_state.bump,
+        seeds::program = x3_pepe::ID
     )]
-    pub stake_vault: Account<'info, TokenAccount>,
+    pub external_state: Account<'info, UserAccount>,
 
     #[account(
-        mut,
-        associated_token::mint = pool.staking_mint,
-        associated_token::authority = owner
+        init_if_needed,
+        payer = owner,
+        space = VoteLedger::LEN,
+        seeds = [b"votes", pool.key().as_ref(), owner.key().as_ref()],
+        bump
     )]
-    pub to_user_stake_ata: Account<'info, TokenAccount>,
+    pub vote_ledger: Account<'info, VoteLedger>,
+
+    pub system_program: Program<'info, System>,
+}
 
+#[derive(Accounts)]
+pub struct GetVotingWeight<'info> {
     #[account(
-        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner
+        seeds = [b"votes", vote_ledger.pool.as_ref(), vote_ledger.owner.as_ref()],
+        bump = vote_ledger.bump
     )]
-    pub external_state: Account<'info, UserAccount>,
+    pub vote_ledger: Account<'info, VoteLedger>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshVotingWeight<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"user", pool.key().as_ref(), owner.key().as_ref()],
+        bump,
+        constraint = user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
+    )]
+    pub user_stake: Account<'info, UserStake>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        seeds = [b"votes", pool.key().as_ref(), owner.key().as_ref()],
+        bump = vote_ledger.bump
+    )]
+    pub vote_ledger: Account<'info, VoteLedger>,
 }
 
 #[derive(Accounts)]
 pub struct UpdateUserRewards<'info> {
     #[account(
+        mut,
         seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
-        bump = pool.bump
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
     )]
     pub pool: Account<'info, Pool>,
 
     #[account(
         mut,
         seeds = [b"user", pool.key().as_ref(), user_stake.owner.as_ref()],
-        bump
+        bump,
+        constraint = user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
     )]
     pub user_stake: Account<'info, UserStake>,
 
     #[account(
-        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), user_stake.owner.as_ref()],
+        bump = external_state.bump,
+        seeds::program = x3_pepe::ID
     )]
     pub external_state: Account<'info, UserAccount>,
 }
 
+#[derive(Accounts)]
+pub struct TransferPosition<'info> {
+    pub from_owner: Signer<'info>,
+
+    // Pays for any account the recipient does not have yet
+    #[account(mut)]
+    pub to_owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"user", pool.key().as_ref(), from_owner.key().as_ref()],
+        bump,
+        constraint = from_user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
+    )]
+    pub from_user_stake: Account<'info, UserStake>,
+
+    #[account(
+        init_if_needed,
+        payer = to_owner,
+        space = UserStake::LEN,
+        seeds = [b"user", pool.key().as_ref(), to_owner.key().as_ref()],
+        bump,
+        constraint = to_user_stake.to_account_info().data_len() >= UserStake::LEN @ StakingError::MigrationRequired
+    )]
+    pub to_user_stake: Account<'info, UserStake>,
+
+    #[account(
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), from_owner.key().as_ref()],
+        bump = from_external_state.bump,
+        seeds::program = x3_pepe::ID
+    )]
+    pub from_external_state: Account<'info, UserAccount>,
+
+    #[account(
+        owner = x3_pepe::ID @ StakingError::InvalidExternalStateOwner,
+        seeds = [b"user", pool.x3_game_seed().as_ref(), to_owner.key().as_ref()],
+        bump = to_external_state.bump,
+        seeds::program = x3_pepe::ID
+    )]
+    pub to_external_state: Account<'info, UserAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = to_owner,
+        space = VoteLedger::LEN,
+        seeds = [b"votes", pool.key().as_ref(), from_owner.key().as_ref()],
+        bump
+    )]
+    pub from_vote_ledger: Account<'info, VoteLedger>,
+
+    #[account(
+        init_if_needed,
+        payer = to_owner,
+        space = VoteLedger::LEN,
+        seeds = [b"votes", pool.key().as_ref(), to_owner.key().as_ref()],
+        bump
+    )]
+    pub to_vote_ledger: Account<'info, VoteLedger>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateUserRewardsBatch<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    /// CHECK: Legacy layout, checked by discriminator, size and address and rewritten by the handler.
+    #[account(mut, owner = crate::ID)]
+    pub pool: UncheckedAccount<'info>,
+    // The pool's vault, checked against its address by the handler
+    pub stake_vault: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUser<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"pool", pool.initializer.as_ref(), pool.staking_mint.as_ref()],
+        bump = pool.bump,
+        constraint = pool.to_account_info().data_len() >= Pool::LEN @ StakingError::MigrationRequired
+    )]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Legacy layout, checked by discriminator, size and address and rewritten by the handler.
+    #[account(mut, owner = crate::ID)]
+    pub user_stake: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[error_code]
 pub enum StakingError {
     #[msg("Unauthorized")]
@@ -822,6 +3221,74 @@ pub enum StakingError {
     StakingPeriodNotEnded,
     #[msg("Nothing to unstake for this level")]
     NothingToUnstake,
+    #[msg("Partial unstake amount must be positive and below the staked amount")]
+    InvalidUnstakeAmount,
+    #[msg("This level has an unbonding period, use request_unstake and withdraw")]
+    UnbondingRequired,
+    #[msg("This level has no unbonding period")]
+    UnbondingNotEnabled,
+    #[msg("Unstake already requested for this level")]
+    UnbondingAlreadyRequested,
+    #[msg("No unstake request for this level")]
+    UnbondingNotRequested,
+    #[msg("Unbonding period has not ended")]
+    UnbondingNotEnded,
+    #[msg("Staking cap for this level has been reached")]
+    LevelCapReached,
+    #[msg("Staking cap for this pool has been reached")]
+    PoolCapReached,
+    #[msg("Too many APY tiers for a level")]
+    TooManyApyTiers,
+    #[msg("Voting weight checkpoint for this slot has been pruned")]
+    CheckpointUnavailable,
+    #[msg("Invalid governance config")]
+    InvalidGovernanceConfig,
+    #[msg("Invalid proposal")]
+    InvalidProposal,
+    #[msg("Voting weight below the proposal threshold")]
+    ProposalThresholdNotMet,
+    #[msg("Voting on this proposal has closed")]
+    VotingClosed,
+    #[msg("No voting weight at the proposal snapshot")]
+    NoVotingWeight,
+    #[msg("Voting or timelock has not ended")]
+    TimelockNotEnded,
+    #[msg("Proposal did not pass")]
+    ProposalNotPassed,
+    #[msg("Proposal already executed")]
+    ProposalAlreadyExecuted,
+    #[msg("Account required to execute this proposal is missing")]
+    MissingExecutionAccount,
+    #[msg("Too many referral reward levels")]
+    TooManyReferralLevels,
+    #[msg("Referral account for an upline not provided")]
+    MissingReferralAccount,
+    #[msg("Nothing to claim")]
+    NothingToClaim,
+    #[msg("Not enough remaining accounts for the batch")]
+    MissingBatchAccounts,
+    #[msg("Invalid user stake account in batch")]
+    InvalidBatchAccount,
+    #[msg("Cannot transfer a position to the same owner")]
+    InvalidTransfer,
+    #[msg("Recipient already has a position on this level")]
+    PositionSlotOccupied,
+    #[msg("Too many lock options for a level")]
+    TooManyLockOptions,
+    #[msg("Lock option not offered on this level")]
+    InvalidLockOption,
+    #[msg("Existing position uses a different lock option")]
+    LockOptionMismatch,
+    #[msg("Level or lock option still has active stakers")]
+    LimitsInUse,
+    #[msg("Execution window of the proposal has passed")]
+    ProposalExpired,
+    #[msg("Account is already in the current layout")]
+    AlreadyMigrated,
+    #[msg("Account does not belong to this pool")]
+    InvalidMigrationAccount,
+    #[msg("Account must be migrated to the current layout first")]
+    MigrationRequired,
 }
 
 
@@ -831,6 +3298,7 @@ pub struct InitializePoolEvent {
     pub authority: Pubkey,
     pub staking_mint: Pubkey,
     pub pool: Pubkey,
+    pub x3_game_id: u64,
 }
 
 #[event]
@@ -840,6 +3308,13 @@ pub struct DepositRewardsEvent {
     pub pool: Pubkey,
 }
 
+#[event]
+pub struct SyncRewardsEvent {
+    pub pool: Pubkey,
+    pub amount: u64,
+    pub reward_reserve: u64,
+}
+
 #[event]
 pub struct InitUserEvent {
     pub owner: Pubkey,
@@ -853,6 +3328,36 @@ pub struct UpdateLimitsEvent {
     pub pool: Pubkey,
 }
 
+#[event]
+pub struct UpdatePoolCapEvent {
+    pub authority: Pubkey,
+    pub staking_cap: u64,
+    pub pool: Pubkey,
+}
+
+#[event]
+pub struct UpdateReferralRewardsEvent {
+    pub authority: Pubkey,
+    pub referral_bps_by_depth: Vec<u64>,
+    pub pool: Pubkey,
+}
+
+#[event]
+pub struct ReferralRewardEvent {
+    pub pool: Pubkey,
+    pub upline: Pubkey,
+    pub from: Pubkey,
+    pub depth: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ClaimReferralRewardsEvent {
+    pub wallet: Pubkey,
+    pub amount: u64,
+    pub pool: Pubkey,
+}
+
 #[event]
 pub struct UpdateAuthorityEvent {
     pub old_authority: Pubkey,
@@ -866,6 +3371,7 @@ pub struct NewStakeEvent {
     pub pool: Pubkey,
     pub level: u8,
     pub amount: u64,
+    pub lock_option: u8,
 }
 
 #[event]
@@ -883,6 +3389,8 @@ pub struct RestakeEvent {
     pub pool: Pubkey,
     pub level: u8,
     pub amount: u64,
+    pub streak: u64,
+    pub lock_option: u8,
 }
 
 #[event]
@@ -912,3 +3420,92 @@ pub struct UnstakeEvent {
     pub amount: u64,
     pub rewards: u64,
 }
+
+#[event]
+pub struct InitGovernanceEvent {
+    pub governance: Pubkey,
+    pub pool: Pubkey,
+    pub voting_period: u64,
+    pub timelock: u64,
+    pub quorum: u64,
+    pub proposal_threshold: u64,
+}
+
+#[event]
+pub struct ProposalCreatedEvent {
+    pub governance: Pubkey,
+    pub proposal: Pubkey,
+    pub id: u64,
+    pub proposer: Pubkey,
+    pub snapshot_slot: u64,
+    pub voting_ends_at: u64,
+}
+
+#[event]
+pub struct VoteCastEvent {
+    pub proposal: Pubkey,
+    pub voter: Pubkey,
+    pub support: bool,
+    pub weight: u64,
+}
+
+#[event]
+pub struct ProposalExecutedEvent {
+    pub governance: Pubkey,
+    pub proposal: Pubkey,
+    pub id: u64,
+}
+
+#[event]
+pub struct VotingWeightEvent {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub slot: u64,
+    pub weight: u64,
+}
+
+#[event]
+pub struct TransferPositionEvent {
+    pub pool: Pubkey,
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub level: u8,
+    pub amount: u64,
+    pub rewards: u64,
+}
+
+#[event]
+pub struct UnstakeRequestedEvent {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub level: u8,
+    pub amount: u64,
+    pub rewards: u64,
+    pub withdrawable_at: u64,
+}
+
+#[event]
+pub struct PartialUnstakeEvent {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub level: u8,
+    pub amount: u64,
+    pub rewards: u64,
+    pub remaining: u64,
+    pub relocked: bool,
+}
+
+#[event]
+pub struct AccountMigratedEvent {
+    pub account: Pubkey,
+    pub old_size: u32,
+    pub new_size: u32,
+}
+
+#[event]
+pub struct RewardShortfallEvent {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    // Rewards the reserve could not fund, forfeited.
+    pub amount: u64,
+}
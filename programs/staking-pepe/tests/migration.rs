@@ -0,0 +1,169 @@
+use anchor_lang::prelude::*;
+use staking_pepe::{
+    ApyTier, Boost, LegacyPool, LegacyStakingInfo, LegacyStakingLimit, LegacyUserStake,
+    LevelStats, LockOption, Loyalty, Pool, StakingInfo, StakingLimit, UserStake,
+};
+
+// Mirrors the program's level and tier bounds.
+const MAX_LEVELS: usize = 14;
+const MAX_APY_TIERS: usize = 4;
+const MAX_LOCK_OPTIONS: usize = 4;
+const MAX_REFERRAL_DEPTH: usize = 5;
+
+// Sizes the original program allocated, which `migrate_pool` / `migrate_user` tell apart from
+// current accounts by length.
+const LEGACY_POOL_LEN: usize = 8 + 32 + 32 + 32 + 8 + 1 + 8 + MAX_LEVELS * (8 + 8 + 8 + 8 + 8 + 8 + 8);
+const LEGACY_USER_STAKE_LEN: usize = 8 + 32 + 32 + (8 + 8 + 8 + 8 + 8 + 8) * MAX_LEVELS;
+
+fn serialized<T: AccountSerialize>(account: &T) -> Vec<u8> {
+    let mut data = Vec::new();
+    account.try_serialize(&mut data).unwrap();
+    data
+}
+
+// Account data as the original program left it: the discriminator the current type still
+// uses, the legacy fields and zero padding up to the legacy allocation.
+fn legacy_account<T: Discriminator>(fields: &impl AnchorSerialize, len: usize) -> Vec<u8> {
+    let mut data = T::DISCRIMINATOR.to_vec();
+    fields.serialize(&mut data).unwrap();
+    assert!(data.len() <= len);
+    data.resize(len, 0);
+    data
+}
+
+fn boost() -> Boost {
+    Boost {
+        apy: 500,
+        period: 86_400,
+    }
+}
+
+fn full_limit() -> StakingLimit {
+    StakingLimit {
+        min: 1,
+        max: u64::MAX,
+        period: 86_400,
+        apy_bps: 1_000,
+        boost_by_cycle: boost(),
+        unbonding_period: 3_600,
+        cap: u64::MAX,
+        apy_tiers: vec![
+            ApyTier {
+                min_amount: 1,
+                apy_bps: 1_000,
+            };
+            MAX_APY_TIERS
+        ],
+        loyalty: Loyalty {
+            bonus_bps_per_restake: 100,
+            max_bonus_bps: 1_000,
+            grace_period: 3_600,
+        },
+        lock_options: vec![
+            LockOption {
+                period: 86_400,
+                apy_bps: 2_000,
+                boost_by_cycle: boost(),
+            };
+            MAX_LOCK_OPTIONS
+        ],
+    }
+}
+
+#[test]
+fn full_accounts_fit_their_allocation() {
+    let pool = Pool {
+        authority: Pubkey::new_unique(),
+        initializer: Pubkey::new_unique(),
+        staking_mint: Pubkey::new_unique(),
+        total_staked: 0,
+        staking_limits_by_level: vec![full_limit(); MAX_LEVELS],
+        bump: 255,
+        staking_cap: 0,
+        level_stats: vec![
+            LevelStats {
+                total_staked: 0,
+                active_stakers: 0,
+                rewards_accrued: 0,
+                rewards_paid: 0,
+                boost_rewards_paid: 0,
+            };
+            MAX_LEVELS
+        ],
+        referral_bps_by_depth: vec![0; MAX_REFERRAL_DEPTH],
+        reward_reserve: 0,
+        rewards_owed: 0,
+        x3_game_id: 0,
+    };
+    assert!(serialized(&pool).len() <= Pool::LEN);
+
+    let user_stake = UserStake {
+        pool: Pubkey::new_unique(),
+        owner: Pubkey::new_unique(),
+        staking_by_level: vec![StakingInfo::default(); MAX_LEVELS],
+    };
+    assert!(serialized(&user_stake).len() <= UserStake::LEN);
+}
+
+#[test]
+fn legacy_pool_keeps_limits_and_starts_untracked() {
+    let authority = Pubkey::new_unique();
+    let legacy = LegacyPool {
+        authority,
+        initializer: authority,
+        staking_mint: Pubkey::new_unique(),
+        total_staked: 5_000,
+        staking_limits_by_level: vec![LegacyStakingLimit {
+            min: 10,
+            max: 1_000,
+            period: 86_400,
+            apy_bps: 1_200,
+            boost_by_cycle: boost(),
+        }],
+        bump: 254,
+    };
+    let data = legacy_account::<Pool>(&legacy, LEGACY_POOL_LEN);
+    assert!(data.len() < Pool::LEN);
+    let pool = Pool::from(LegacyPool::deserialize(&mut &data[8..]).unwrap());
+
+    assert_eq!(pool.authority, authority);
+    assert_eq!(pool.staking_mint, legacy.staking_mint);
+    assert_eq!(pool.total_staked, 5_000);
+    assert_eq!(pool.bump, 254);
+    assert_eq!(pool.x3_game_id, 0);
+    assert!(pool.level_stats.is_empty());
+    let limits = &pool.staking_limits_by_level[0];
+    assert_eq!((limits.min, limits.max, limits.period), (10, 1_000, 86_400));
+    assert_eq!(limits.apy_for(1_000, 0), 1_200);
+    assert_eq!(limits.period_for(1), 86_400);
+    assert!(limits.lock_options.is_empty() && limits.apy_tiers.is_empty());
+}
+
+#[test]
+fn legacy_user_stake_keeps_positions() {
+    let legacy = LegacyUserStake {
+        pool: Pubkey::new_unique(),
+        owner: Pubkey::new_unique(),
+        staking_by_level: vec![LegacyStakingInfo {
+            amount_staked: 700,
+            rewards_accrued: 30,
+            last_update_ts: 1_000,
+            start_staking_time: 900,
+            boost_rewards_total: 12,
+            cycle_used: 2,
+        }],
+    };
+    let data = legacy_account::<UserStake>(&legacy, LEGACY_USER_STAKE_LEN);
+    assert!(data.len() < UserStake::LEN);
+    let user_stake = UserStake::from(LegacyUserStake::deserialize(&mut &data[8..]).unwrap());
+
+    assert_eq!(user_stake.pool, legacy.pool);
+    assert_eq!(user_stake.owner, legacy.owner);
+    let info = &user_stake.staking_by_level[0];
+    assert_eq!(info.amount_staked, 700);
+    assert_eq!(info.rewards_accrued, 30);
+    assert_eq!(info.start_staking_time, 900);
+    assert_eq!(info.cycle_used, 2);
+    assert_eq!(info.boost_rewards_total - info.boost_rewards_paid, 0);
+    assert_eq!((info.unbonding_start, info.streak, info.lock_option), (0, 0, 0));
+}
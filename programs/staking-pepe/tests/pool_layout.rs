@@ -0,0 +1,52 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hash;
+use staking_pepe::Pool;
+
+// x3_pepe reads `Pool` through raw offsets in `set_staking_pool`; these pin that layout.
+
+fn pool(staking_mint: Pubkey, x3_game_id: u64) -> Pool {
+    Pool {
+        authority: Pubkey::new_unique(),
+        initializer: Pubkey::new_unique(),
+        staking_mint,
+        total_staked: 0,
+        staking_limits_by_level: Vec::new(),
+        bump: 255,
+        staking_cap: 0,
+        level_stats: Vec::new(),
+        referral_bps_by_depth: Vec::new(),
+        reward_reserve: 0,
+        rewards_owed: 0,
+        x3_game_id,
+    }
+}
+
+#[test]
+fn staking_program_id_matches_x3() {
+    assert_eq!(staking_pepe::ID, x3_pepe::STAKING_PROGRAM_ID);
+}
+
+#[test]
+fn staking_mint_at_x3_offset() {
+    let staking_mint = Pubkey::new_unique();
+    let mut data = Vec::new();
+    pool(staking_mint, 0).try_serialize(&mut data).unwrap();
+
+    assert_eq!(data[..8], hash(b"account:Pool").to_bytes()[..8]);
+    let offset = x3_pepe::STAKING_POOL_MINT_OFFSET;
+    assert_eq!(data[offset..offset + 32], staking_mint.to_bytes());
+}
+
+#[test]
+fn x3_user_key_uses_pool_game() {
+    let wallet = Pubkey::new_unique();
+    let legacy = Pubkey::find_program_address(&[b"user", wallet.as_ref()], &x3_pepe::ID).0;
+    assert_eq!(pool(Pubkey::new_unique(), 0).x3_user_key(&wallet), legacy);
+
+    let game = Pubkey::find_program_address(
+        &[b"user", 3u64.to_le_bytes().as_ref(), wallet.as_ref()],
+        &x3_pepe::ID,
+    )
+    .0;
+    assert_eq!(pool(Pubkey::new_unique(), 3).x3_user_key(&wallet), game);
+}
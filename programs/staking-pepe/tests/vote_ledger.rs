@@ -0,0 +1,67 @@
+use anchor_lang::prelude::*;
+use staking_pepe::{VoteLedger, MAX_CHECKPOINTS};
+
+fn ledger() -> VoteLedger {
+    VoteLedger {
+        pool: Pubkey::new_unique(),
+        owner: Pubkey::new_unique(),
+        bump: 255,
+        pruned: false,
+        checkpoints: Vec::new(),
+    }
+}
+
+#[test]
+fn weight_by_slot_and_time() {
+    let mut ledger = ledger();
+    ledger.record(10, 1_000, 500);
+    ledger.record(20, 1_008, 800);
+    // A second checkpoint in the same slot replaces the first
+    ledger.record(20, 1_008, 900);
+
+    assert_eq!(ledger.checkpoints.len(), 2);
+    assert_eq!(ledger.weight_at(9).unwrap(), 0);
+    assert_eq!(ledger.weight_at(10).unwrap(), 500);
+    assert_eq!(ledger.weight_at(19).unwrap(), 500);
+    assert_eq!(ledger.weight_at(25).unwrap(), 900);
+
+    assert_eq!(ledger.weight_at_time(999).unwrap(), 0);
+    assert_eq!(ledger.weight_at_time(1_004).unwrap(), 500);
+    assert_eq!(ledger.weight_at_time(1_008).unwrap(), 900);
+}
+
+#[test]
+fn pruned_history_is_unavailable() {
+    let mut ledger = ledger();
+    for i in 0..=MAX_CHECKPOINTS as u64 {
+        ledger.record(100 + i, 10_000 + i, i);
+    }
+
+    assert_eq!(ledger.checkpoints.len(), MAX_CHECKPOINTS);
+    assert!(ledger.pruned);
+    assert!(ledger.weight_at(100).is_err());
+    assert!(ledger.weight_at_time(10_000).is_err());
+    assert_eq!(ledger.weight_at(101).unwrap(), 1);
+    assert_eq!(
+        ledger
+            .weight_at_time(10_000 + MAX_CHECKPOINTS as u64)
+            .unwrap(),
+        MAX_CHECKPOINTS as u64
+    );
+}
+
+#[test]
+fn only_the_owner_refreshes_its_ledger() {
+    // Anyone else could fill the ledger and prune the checkpoint an open proposal reads
+    let owner = Pubkey::new_unique();
+    let metas = staking_pepe::accounts::RefreshVotingWeight {
+        owner,
+        pool: Pubkey::new_unique(),
+        user_stake: Pubkey::new_unique(),
+        vote_ledger: Pubkey::new_unique(),
+    }
+    .to_account_metas(None);
+
+    let owner_meta = metas.iter().find(|meta| meta.pubkey == owner).unwrap();
+    assert!(owner_meta.is_signer);
+}
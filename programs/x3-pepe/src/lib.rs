@@ -1,24 +1,52 @@
 #![allow(deprecated)]
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{hash::hash, pubkey};
 use anchor_lang::system_program;
-use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token::{Mint, Token, TokenAccount, Transfer};
+use anchor_spl::associated_token::{get_associated_token_address_with_program_id, AssociatedToken};
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+};
+use anchor_spl::token::spl_token::native_mint;
+use anchor_spl::token_interface::{self, Burn, Mint, SyncNative, TokenAccount, TokenInterface, TransferChecked};
 
 declare_id!("6hFfJP3EgJe8mL9rhBjXWhruJWHBaWnt8gvej14xDUuz");
 
+/// Upper bound on levels, matching the price space reserved in `GlobalState`.
+pub const MAX_LEVELS: usize = 14;
+/// Share of a released freeze burned by default (0.5%).
+pub const DEFAULT_BURN_BPS: u64 = 50;
+/// Layout version of `GlobalState`; older accounts need `migrate_global`.
+pub const GLOBAL_STATE_VERSION: u8 = 1;
+/// Max paying places per matrix cycle.
+pub const MAX_SLOTS: usize = 6;
+/// Places on the first line of an X4 matrix.
+pub const X4_FIRST_LINE: u8 = 2;
+
+/// staking_pepe program, whose pool vaults can be required as `staking_token_account`.
+pub const STAKING_PROGRAM_ID: Pubkey = pubkey!("9Mq2JHE2c38LTioLQYjqbLDd4jJkgftLj83VYiTYrfnJ");
+/// Offset of `staking_mint` in a staking_pepe `Pool` account: discriminator (8), authority (32),
+/// initializer (32). staking_pepe tests this against its own layout.
+pub const STAKING_POOL_MINT_OFFSET: usize = 8 + 32 + 32;
+/// Delay between `clear_staking_pool` and `update_global` being able to redirect staking (48h).
+pub const STAKING_POOL_UNLOCK_DELAY: i64 = 48 * 60 * 60;
+
 #[program]
 pub mod x3_pepe {
     use super::*;
-    use anchor_spl::token;
 
     /// Initializes the X3MonsterBall program, setting up global config and the root user.
+    /// Each `game_id` is an independent matrix with its own mint, prices, root and vault;
+    /// game 0 keeps the original single-matrix addresses.
     pub fn initialize(
         ctx: Context<Initialize>,
         root: Pubkey,
         owner: Pubkey,
         prices: Vec<u64>,
+        game_id: u64,
     ) -> Result<()> {
         let global = &mut ctx.accounts.global_state;
+        global.game_id = game_id;
         global.root = root;
         global.owner = owner;
         global.token_mint = ctx.accounts.token_mint.key();
@@ -29,29 +57,99 @@ pub mod x3_pepe {
         global.root_bump = ctx.bumps.root_account;
         global.vault_token_account = ctx.accounts.vault_token_account.key();
         global.staking_token_account = ctx.accounts.staking_token_account.key();
+        global.staking_pool = Pubkey::default();
+        global.sol_vault_bump = 0;
+        global.matrix_types = Vec::new();
+        global.slot_shares = Vec::new();
+        global.burn_bps = DEFAULT_BURN_BPS;
+        global.burn_destination = BurnDestination::Staking;
+        global.treasury = Pubkey::default();
+        global.protocol_fee_bps = 0;
+        global.staking_pool_unlock_time = 0;
+        global.version = GLOBAL_STATE_VERSION;
+
+        init_root_account(
+            &mut ctx.accounts.root_account,
+            root,
+            global.max_level,
+            ctx.bumps.root_account,
+        )?;
 
-        let root_acct = &mut ctx.accounts.root_account;
-        root_acct.wallet = root;
-        root_acct.referrer = Pubkey::default(); // root has no referrer (treated as top)
-        root_acct.balance = 0;
-        root_acct.matrix = Vec::with_capacity(global.max_level as usize);
-        root_acct.closed_cycles = Vec::with_capacity(10);
-        root_acct.bump = ctx.bumps.root_account;
-
-        for _ in 0..global.max_level {
-            root_acct.matrix.push(X3 {
-                blocked: false,
-                referrals: 0,
-                cycles: 0,
-                freeze: 0,
-                level_bought_time: Clock::get()?.unix_timestamp as u64,
-                close_level_time: 0,
-            });
+        Ok(())
+    }
+
+
+    /// Initializes a SOL-denominated matrix: level prices are in lamports, paid into the
+    /// `sol_vault` PDA, and the staking share is wrapped into a wrapped-SOL token account.
+    pub fn initialize_sol(
+        ctx: Context<InitializeSol>,
+        root: Pubkey,
+        owner: Pubkey,
+        prices: Vec<u64>,
+        game_id: u64,
+    ) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        global.game_id = game_id;
+        global.root = root;
+        global.owner = owner;
+        global.token_mint = Pubkey::default();
+        global.paused = false;
+        global.max_level = prices.len() as u8;
+        global.prices = prices;
+        global.bump = ctx.bumps.global_state;
+        global.root_bump = ctx.bumps.root_account;
+        global.vault_token_account = ctx.accounts.sol_vault.key();
+        global.staking_token_account = ctx.accounts.staking_token_account.key();
+        global.staking_pool = Pubkey::default();
+        global.sol_vault_bump = ctx.bumps.sol_vault;
+        global.matrix_types = Vec::new();
+        global.slot_shares = Vec::new();
+        global.burn_bps = DEFAULT_BURN_BPS;
+        global.burn_destination = BurnDestination::Staking;
+        global.treasury = Pubkey::default();
+        global.protocol_fee_bps = 0;
+        global.staking_pool_unlock_time = 0;
+        global.version = GLOBAL_STATE_VERSION;
+
+        // Keep the vault rent-exempt so payouts can drain it down to the reserve
+        let rent_reserve = Rent::get()?
+            .minimum_balance(0)
+            .saturating_sub(ctx.accounts.sol_vault.lamports());
+        if rent_reserve > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.initializer.to_account_info(),
+                        to: ctx.accounts.sol_vault.to_account_info(),
+                    },
+                ),
+                rent_reserve,
+            )?;
         }
 
+        init_root_account(
+            &mut ctx.accounts.root_account,
+            root,
+            global.max_level,
+            ctx.bumps.root_account,
+        )?;
+
         Ok(())
     }
 
+    /// Upgrades a `GlobalState` created before the configurable matrix, burn and fee settings:
+    /// grows it to the current size and sets the defaults its zeroed new fields would miss.
+    pub fn migrate_global(ctx: Context<MigrateGlobal>) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        require!(
+            global.version < GLOBAL_STATE_VERSION,
+            ContractError::AlreadyMigrated
+        );
+        global.burn_bps = DEFAULT_BURN_BPS;
+        global.version = GLOBAL_STATE_VERSION;
+        Ok(())
+    }
 
     pub fn update_prices(
         ctx: Context<UpdatePrices>,
@@ -69,6 +167,7 @@ pub mod x3_pepe {
                 freeze: 0,
                 level_bought_time: Clock::get()?.unix_timestamp as u64,
                 close_level_time: 0,
+                first_line: 0,
             });
             is_increase = true;
         }
@@ -93,12 +192,151 @@ pub mod x3_pepe {
         }
         
         if let Some(staking_token_account) = new_staking_token_account {
+            if global.staking_pool != Pubkey::default() {
+                // The lock only lifts once the delay requested by clear_staking_pool has passed
+                require!(
+                    global.staking_pool_unlock_time != 0
+                        && Clock::get()?.unix_timestamp >= global.staking_pool_unlock_time,
+                    ContractError::StakingPoolLocked
+                );
+                global.staking_pool = Pubkey::default();
+                global.staking_pool_unlock_time = 0;
+            }
             global.staking_token_account = staking_token_account;
         }
 
         Ok(())
     }
 
+    /// Points `staking_token_account` at the stake vault of a staking_pepe pool for `token_mint`
+    /// and locks it there: `update_global` can no longer redirect it until `clear_staking_pool`
+    /// and its delay. Also cancels a pending unlock.
+    pub fn set_staking_pool(ctx: Context<SetStakingPool>) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        let staking_pool = &ctx.accounts.staking_pool;
+        let staking_mint = global.staking_mint();
+        verify_staking_pool(staking_pool, staking_mint)?;
+        let staking_token_info = ctx.accounts.staking_token_account.to_account_info();
+        require!(
+            staking_token_info.key()
+                == get_associated_token_address_with_program_id(
+                    &staking_pool.key(),
+                    &staking_mint,
+                    staking_token_info.owner,
+                ),
+            ContractError::InvalidStakingPool
+        );
+
+        global.staking_pool = staking_pool.key();
+        global.staking_token_account = ctx.accounts.staking_token_account.key();
+        global.staking_pool_unlock_time = 0;
+
+        emit!(StakingPoolSetEvent {
+            staking_pool: global.staking_pool,
+            staking_token_account: global.staking_token_account,
+        });
+
+        Ok(())
+    }
+
+    /// Requests removal of the staking pool lock. `update_global` can change
+    /// `staking_token_account` again once `STAKING_POOL_UNLOCK_DELAY` has passed.
+    pub fn clear_staking_pool(ctx: Context<OwnerOnly>) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        require!(
+            global.staking_pool != Pubkey::default(),
+            ContractError::StakingPoolNotLocked
+        );
+        global.staking_pool_unlock_time = Clock::get()?
+            .unix_timestamp
+            .checked_add(STAKING_POOL_UNLOCK_DELAY)
+            .ok_or(ContractError::Overflow)?;
+
+        emit!(StakingPoolUnlockRequestedEvent {
+            staking_pool: global.staking_pool,
+            unlock_time: global.staking_pool_unlock_time,
+        });
+
+        Ok(())
+    }
+
+    /// Sets the matrix layout of each level, X3 for levels not listed. Changing the type of a
+    /// level with open matrices reinterprets their place counters, so set it before launch.
+    pub fn set_matrix_types(ctx: Context<OwnerOnly>, matrix_types: Vec<MatrixType>) -> Result<()> {
+        require!(
+            matrix_types.len() <= MAX_LEVELS,
+            ContractError::LevelOutOfRange
+        );
+        ctx.accounts.global_state.matrix_types = matrix_types;
+        Ok(())
+    }
+
+    /// Sets the paying places of a level's cycle and the share of the price each one freezes
+    /// for the receiver and passes up. Over a cycle the shares must add up to the price of
+    /// every place. An empty list restores the default split. Open matrices keep their place
+    /// count and take the new shares from their next place, closing at once if the count has
+    /// reached the new number of places, so set it before launch.
+    pub fn set_slot_shares(ctx: Context<OwnerOnly>, level: u8, slots: Vec<SlotShare>) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        require!(level < global.max_level, ContractError::LevelOutOfRange);
+        if !slots.is_empty() {
+            validate_slot_shares(&slots)?;
+        }
+
+        let lvl = level as usize;
+        if global.slot_shares.len() <= lvl {
+            global.slot_shares.resize(lvl + 1, Vec::new());
+        }
+        global.slot_shares[lvl] = slots;
+        Ok(())
+    }
+
+    /// Sets the share of released freezes that is burned and where it goes: burned from the
+    /// vault (token mode only), forwarded to staking, or paid to `treasury`.
+    pub fn set_burn_config(
+        ctx: Context<OwnerOnly>,
+        burn_bps: u64,
+        burn_destination: BurnDestination,
+        treasury: Option<Pubkey>,
+    ) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        require!(burn_bps <= 10_000, ContractError::InvalidBurnConfig);
+        if let Some(treasury) = treasury {
+            global.treasury = treasury;
+        }
+        match burn_destination {
+            BurnDestination::Burn => require!(!global.is_sol_mode(), ContractError::InvalidBurnConfig),
+            BurnDestination::Treasury => require!(
+                global.treasury != Pubkey::default(),
+                ContractError::InvalidBurnConfig
+            ),
+            BurnDestination::Staking => {}
+        }
+        global.burn_bps = burn_bps;
+        global.burn_destination = burn_destination;
+        Ok(())
+    }
+
+    /// Sets the protocol fee taken from every level purchase before the matrix split and paid
+    /// to `treasury`. 0 disables it.
+    pub fn set_protocol_fee(
+        ctx: Context<OwnerOnly>,
+        protocol_fee_bps: u64,
+        treasury: Option<Pubkey>,
+    ) -> Result<()> {
+        let global = &mut ctx.accounts.global_state;
+        require!(protocol_fee_bps <= 10_000, ContractError::InvalidProtocolFee);
+        if let Some(treasury) = treasury {
+            global.treasury = treasury;
+        }
+        require!(
+            protocol_fee_bps == 0 || global.treasury != Pubkey::default(),
+            ContractError::InvalidProtocolFee
+        );
+        global.protocol_fee_bps = protocol_fee_bps;
+        Ok(())
+    }
+
     /// Registers a new user in the matrix. If called by the user themselves, `user_key` should be their own wallet.
     /// If called by a sponsor on behalf of someone, `user_key` is the new user's wallet and the transaction payer provides funds.
     pub fn registration<'link, 'info>(
@@ -111,6 +349,10 @@ pub mod x3_pepe {
     {
         let global = &ctx.accounts.global_state;
         require!(!global.paused, ContractError::ContractPaused);
+        require!(
+            global.version == GLOBAL_STATE_VERSION,
+            ContractError::MigrationRequired
+        );
 
         let payer = &ctx.accounts.payer;
         let user_account = &mut ctx.accounts.user_account;
@@ -137,6 +379,7 @@ pub mod x3_pepe {
         user_account.matrix = Vec::with_capacity(global.max_level as usize);
         user_account.closed_cycles = Vec::with_capacity(10);
         user_account.bump = ctx.bumps.user_account;
+        user_account.auto_upgrade = false;
         user_account.matrix.push(X3 {
             blocked: false,
             referrals: 0,
@@ -144,86 +387,65 @@ pub mod x3_pepe {
             freeze: 0,
             level_bought_time: Clock::get()?.unix_timestamp as u64,
             close_level_time: 0,
+            first_line: 0,
         });
 
         let price = global.prices[0];
 
-        let payer_token_acct = &ctx.accounts.payer_token_account;
-        let vault_token_acct = &ctx.accounts.vault_token_account;
-        let staking_token_acct = &ctx.accounts.staking_token_account;
-        // Ensure token accounts are correct
-        require!(
-            payer_token_acct.owner == payer.key(),
-            ContractError::InvalidTokenAccount
-        );
-        require!(
-            payer_token_acct.mint == global.token_mint,
-            ContractError::InvalidTokenAccount
-        );
-        require!(
-            vault_token_acct.key() == global.vault_token_account,
-            ContractError::InvalidVaultAccount
-        );
-        // Transfer tokens from payer to vault (authority = payer who is a Signer)
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.as_ref().to_account_info(),
-                Transfer {
-                    from: payer_token_acct.to_account_info(),
-                    to: vault_token_acct.to_account_info(),
-                    authority: payer.to_account_info(),
-                },
-            ),
-            price,
-        )?;
+        let vault = Vault {
+            global,
+            token_account: ctx.accounts.vault_token_account.as_ref(),
+            token_mint: ctx.accounts.token_mint.as_ref(),
+            sol_vault: ctx.accounts.sol_vault.as_ref(),
+            token_program: &ctx.accounts.token_program,
+            system_program: &ctx.accounts.system_program,
+        };
+        vault.collect(payer, ctx.accounts.payer_token_account.as_ref(), price)?;
+
+        // The protocol fee comes off the top, the matrix splits the rest
+        let protocol_fee = share_of_price(price, global.protocol_fee_bps)?;
+        if protocol_fee > 0 {
+            let treasury = ctx.accounts.treasury.as_ref().ok_or(ContractError::InvalidTreasury)?;
+            vault.pay_out(treasury.to_account_info(), protocol_fee)?;
+            emit!(ProtocolFeeEvent {
+                user: user_key,
+                level: 0,
+                amount: protocol_fee,
+                treasury: treasury.key(),
+            });
+        }
+        let split_price = price - protocol_fee;
 
         let mut receiver_acct = find_receiver_account(
             user_account,
             user_key,
             0,
+            &global.game_seed(),
             ctx.program_id,
             ctx.remaining_accounts,
         )?;
 
-        let receiver_account_info = receiver_acct.to_account_info();
-        let mut send_to_staking = 0;
-        if receiver_acct.wallet == user_account.referrer {
-            send_to_staking += update_matrix(
-                &mut receiver_acct,
-                receiver_account_info,
-                user_key,
-                0,
-                global,
-                ctx.accounts.payer.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            )?.unwrap_or_default();
-        }
-
-        send_to_staking += distribute_dividends(
+        let matrix = MatrixContext {
+            global,
+            payer: ctx.accounts.payer.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            program_id: ctx.program_id,
+            remaining_accounts: ctx.remaining_accounts,
+        };
+        let (payout, upline_acct) = place_in_matrix(
             user_account,
             &mut receiver_acct,
-            global,
             user_key,
             0,
-            price,
-            ctx.remaining_accounts,
-        )?.unwrap_or_default();
+            split_price,
+            &matrix,
+        )?;
 
-        if send_to_staking != 0 {
-            let seeds = &[b"state".as_ref(), &[global.bump]];
-            token::transfer(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.as_ref().to_account_info(),
-                    Transfer {
-                        from: vault_token_acct.to_account_info(),
-                        to: staking_token_acct.to_account_info(),
-                        authority: ctx.accounts.global_state.to_account_info(),
-                    },
-                    &[seeds],
-                ),
-                send_to_staking,
-            )?;
-        }
+        vault.settle(
+            &payout,
+            &ctx.accounts.staking_token_account,
+            ctx.accounts.treasury.as_ref(),
+        )?;
 
 
         emit!(RegistrationEvent {
@@ -240,6 +462,15 @@ pub mod x3_pepe {
             receiver_acct.exit(ctx.program_id)?;
         }
 
+        // An X4 spillover also updated the receiver's upline
+        if let Some(upline_acct) = upline_acct {
+            if ctx.accounts.root_account.key() == upline_acct.key() {
+                ctx.accounts.root_account = upline_acct;
+            } else {
+                upline_acct.exit(ctx.program_id)?;
+            }
+        }
+
         Ok(())
     }
 
@@ -250,182 +481,234 @@ pub mod x3_pepe {
         ctx: Context<'_, '_, 'link, 'info, BuyNewLevel<'info>>,
         user_key: Pubkey,
         level: u8,
+        source: PaymentSource,
     ) -> Result<()>
     where
         'link: 'info,
     {
-        let global = &ctx.accounts.global_state;
-        require!(!global.paused, ContractError::ContractPaused);
-
-        let payer = &ctx.accounts.payer;
-        let user_account = &mut ctx.accounts.user_account;
-        let root_acct = &mut ctx.accounts.root_account;
+        let user_account = &ctx.accounts.user_account;
+        // Only the owner can spend their balance
         require!(
-            is_user_exists(user_account, global.root),
-            ContractError::UserNotExists
+            source == PaymentSource::Wallet || user_account.wallet == ctx.accounts.payer.key(),
+            ContractError::UnauthorizedClaim
         );
-        require!(level < global.max_level, ContractError::LevelOutOfRange);
+
+        buy_level(
+            ctx.accounts,
+            ctx.program_id,
+            ctx.remaining_accounts,
+            user_key,
+            level,
+            source,
+        )
+    }
+
+    /// Buys every missing level up to and including `target` in order, each paid and placed
+    /// as in `buy_new_level`. `remaining_accounts` must hold the uplines needed for all of them.
+    pub fn buy_levels_up_to<'link, 'info>(
+        ctx: Context<'_, '_, 'link, 'info, BuyNewLevel<'info>>,
+        user_key: Pubkey,
+        target: u8,
+        source: PaymentSource,
+    ) -> Result<()>
+    where
+        'link: 'info,
+    {
+        let user_account = &ctx.accounts.user_account;
         require!(
-            !is_level_active(user_account, level),
-            ContractError::LevelAlreadyActive
+            source == PaymentSource::Wallet || user_account.wallet == ctx.accounts.payer.key(),
+            ContractError::UnauthorizedClaim
         );
         require!(
-            level == 0 || is_level_active(user_account, level - 1),
-            ContractError::PreviousLevelRequired
+            target < ctx.accounts.global_state.max_level,
+            ContractError::LevelOutOfRange
         );
-
-        if level > 0 && user_account.matrix[(level - 1) as usize].blocked {
-            user_account.matrix[(level - 1) as usize].blocked = false;
+        let first = user_account.matrix.len() as u8;
+        require!(first <= target, ContractError::LevelAlreadyActive);
+
+        for level in first..=target {
+            buy_level(
+                ctx.accounts,
+                ctx.program_id,
+                ctx.remaining_accounts,
+                user_key,
+                level,
+                source,
+            )?;
+            // The root may be loaded again from remaining_accounts for the next level
+            ctx.accounts.root_account.exit(ctx.program_id)?;
         }
-        user_account.matrix.push(X3 {
-            blocked: false,
-            referrals: 0,
-            cycles: 0,
-            freeze: 0,
-            level_bought_time: Clock::get()?.unix_timestamp as u64,
-            close_level_time: 0,
-        });
+        Ok(())
+    }
 
-        // Payment for the level
-        let price = global.prices[level as usize];
+    /// Opts the caller in or out of automatic upgrades through `auto_upgrade`.
+    pub fn set_auto_upgrade(ctx: Context<SetAutoUpgrade>, enabled: bool) -> Result<()> {
+        ctx.accounts.user_account.auto_upgrade = enabled;
+        Ok(())
+    }
 
-        let payer_token_acct = &ctx.accounts.payer_token_account;
-        let vault_token_acct = &ctx.accounts.vault_token_account;
-        let staking_token_acct = &ctx.accounts.staking_token_account;
-        require!(
-            payer_token_acct.owner == payer.key(),
-            ContractError::InvalidTokenAccount
-        );
+    /// Permissionless crank: buys the next level for an opted-in user whose highest level is
+    /// blocked, paid entirely from the user's balance. The caller pays any account resize rent.
+    pub fn auto_upgrade<'link, 'info>(
+        ctx: Context<'_, '_, 'link, 'info, BuyNewLevel<'info>>,
+        user_key: Pubkey,
+    ) -> Result<()>
+    where
+        'link: 'info,
+    {
+        let global = &ctx.accounts.global_state;
+        let user_account = &ctx.accounts.user_account;
+        require!(user_account.auto_upgrade, ContractError::AutoUpgradeDisabled);
+        let level = user_account.matrix.len() as u8;
         require!(
-            payer_token_acct.mint == global.token_mint,
-            ContractError::InvalidTokenAccount
+            level > 0 && level < global.max_level,
+            ContractError::LevelOutOfRange
         );
         require!(
-            vault_token_acct.key() == global.vault_token_account,
-            ContractError::InvalidVaultAccount
+            user_account.matrix[(level - 1) as usize].blocked
+                && user_account.balance >= global.prices[level as usize],
+            ContractError::AutoUpgradeNotReady
         );
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Transfer {
-                    from: payer_token_acct.to_account_info(),
-                    to: vault_token_acct.to_account_info(),
-                    authority: payer.to_account_info(),
-                },
-            ),
-            price,
-        )?;
 
-        let mut receiver_acct = find_receiver_account(
-            user_account,
-            user_key,
-            level,
+        buy_level(
+            ctx.accounts,
             ctx.program_id,
             ctx.remaining_accounts,
-        )?;
-
-        let receiver_account_info = receiver_acct.to_account_info();
-        let mut send_to_staking = 0;
-        if receiver_acct.wallet == user_account.referrer {
-            send_to_staking += update_matrix(
-                &mut receiver_acct,
-                receiver_account_info,
-                user_key,
-                level,
-                global,
-                ctx.accounts.payer.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            )?.unwrap_or_default();
-        }
-
-        send_to_staking += distribute_dividends(
-            user_account,
-            &mut receiver_acct,
-            global,
             user_key,
             level,
-            price,
-            ctx.remaining_accounts,
-        )?.unwrap_or_default();
+            PaymentSource::Balance,
+        )
+    }
 
-        if send_to_staking != 0 {
-            let seeds = &[b"state".as_ref(), &[global.bump]];
-            token::transfer(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.as_ref().to_account_info(),
-                    Transfer {
-                        from: vault_token_acct.to_account_info(),
-                        to: staking_token_acct.to_account_info(),
-                        authority: ctx.accounts.global_state.to_account_info(),
-                    },
-                    &[seeds],
-                ),
-                send_to_staking,
-            )?;
-        }
+    /// Claim accumulated rewards for the caller. Transfers any `balance` to the user's wallet.
+    pub fn claim(ctx: Context<Claim>) -> Result<()> {
+        let global = &ctx.accounts.global_state;
+        let user_acct = &mut ctx.accounts.user_account;
+        let user_wallet = &ctx.accounts.user_wallet;
+        let amount = user_acct.balance;
+        require!(amount > 0, ContractError::NothingToClaim);
 
-        emit!(UpgradeEvent {
-            user: user_key,
-            level,
-            amount: price,
-        });
+        user_acct.balance = 0;
 
-        if root_acct.key() == receiver_acct.key() {
-            ctx.accounts.root_account = receiver_acct;
+        // Pay lamports to the wallet in SOL mode, otherwise tokens to the user's token account.
+        // The balance leaves the vault in full, any transfer fee is taken from what the user receives.
+        let vault = Vault {
+            global,
+            token_account: ctx.accounts.vault_token_account.as_ref(),
+            token_mint: ctx.accounts.token_mint.as_ref(),
+            sol_vault: ctx.accounts.sol_vault.as_ref(),
+            token_program: &ctx.accounts.token_program,
+            system_program: &ctx.accounts.system_program,
+        };
+        let destination = if global.is_sol_mode() {
+            user_wallet.to_account_info()
         } else {
-            receiver_acct.exit(ctx.program_id)?;
-        }
+            ctx.accounts
+                .user_token_account
+                .as_ref()
+                .ok_or(ContractError::MissingPaymentAccount)?
+                .to_account_info()
+        };
+        vault.pay_out(destination, amount)?;
 
+        emit!(ClaimedEvent {
+            user: user_wallet.key(),
+            amount,
+        });
         Ok(())
     }
 
-    /// Claim accumulated rewards for the caller. Transfers any `balance` to the user's wallet.
-    pub fn claim(ctx: Context<Claim>) -> Result<()> {
+    /// Transfers `amount` of the caller's balance to any token account of the payment mint.
+    /// Used by other programs through CPI, e.g. to stake a claim without a wallet round-trip.
+    /// Token mode only.
+    pub fn claim_to(ctx: Context<ClaimTo>, amount: u64) -> Result<()> {
         let global = &ctx.accounts.global_state;
         let user_acct = &mut ctx.accounts.user_account;
-        let user_wallet = &ctx.accounts.user_wallet;
-        let amount = user_acct.balance;
         require!(amount > 0, ContractError::NothingToClaim);
+        require!(amount <= user_acct.balance, ContractError::InsufficientBalance);
 
-        user_acct.balance = 0;
+        user_acct.balance -= amount;
 
-        // Transfer tokens from vault to user's associated token account
-        let vault_acct = &ctx.accounts.vault_token_account;
-        let user_token_acct = &ctx.accounts.user_token_account;
-        require!(
-            user_token_acct.owner == user_wallet.key(),
-            ContractError::InvalidTokenAccount
-        );
-        require!(
-            user_token_acct.mint == global.token_mint,
-            ContractError::InvalidTokenAccount
-        );
-        require!(
-            vault_acct.key() == global.vault_token_account,
-            ContractError::InvalidVaultAccount
-        );
-        // Use global_state (PDA) as authority to sign for vault token account
-        let seeds = &[b"state".as_ref(), &[global.bump]];
-        token::transfer(
+        let vault_token_account = ctx.accounts.vault_token_account.as_ref()
+            .ok_or(ContractError::MissingPaymentAccount)?;
+        let token_mint = ctx.accounts.token_mint.as_ref()
+            .ok_or(ContractError::MissingPaymentAccount)?;
+        let game_seed = global.game_seed();
+        let seeds = &[b"state".as_ref(), game_seed.as_ref(), &[global.bump]];
+        token_interface::transfer_checked(
             CpiContext::new_with_signer(
-                ctx.accounts.token_program.as_ref().to_account_info(),
-                Transfer {
-                    from: vault_acct.to_account_info(),
-                    to: user_token_acct.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: vault_token_account.to_account_info(),
+                    mint: token_mint.to_account_info(),
+                    to: ctx.accounts.destination_token_account.to_account_info(),
                     authority: ctx.accounts.global_state.to_account_info(),
                 },
                 &[seeds],
             ),
             amount,
+            token_mint.decimals,
         )?;
 
-        emit!(ClaimedEvent {
-            user: user_wallet.key(),
+        emit!(ClaimedToEvent {
+            user: ctx.accounts.user_wallet.key(),
+            destination: ctx.accounts.destination_token_account.key(),
             amount,
         });
         Ok(())
     }
 
+    /// Rewrites a `UserAccount` still in the layout from before X4 matrices and auto-upgrades
+    /// into the current one, growing it if needed. Permissionless, the payer covers any rent.
+    pub fn migrate_user(ctx: Context<MigrateUser>, user_key: Pubkey) -> Result<()> {
+        let info = ctx.accounts.user_account.to_account_info();
+        let legacy = {
+            let data = info.try_borrow_data()?;
+            require!(
+                data.len() >= 8 && data[..8] == hash(b"account:UserAccount").to_bytes()[..8],
+                ContractError::AlreadyMigrated
+            );
+            LegacyUserAccount::deserialize(&mut &data[8..])?
+        };
+        let user = UserAccount::from(legacy);
+
+        let new_size = 8 + UserAccount::max_size_with_boosters(
+            ctx.accounts.global_state.max_level as usize,
+            user.closed_cycles.len().max(10),
+        );
+        let current_size = info.data_len();
+        if new_size > current_size {
+            let rent = Rent::get()?;
+            let additional_rent = rent
+                .minimum_balance(new_size)
+                .saturating_sub(info.lamports());
+            if additional_rent > 0 {
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.payer.to_account_info(),
+                            to: info.clone(),
+                        },
+                    ),
+                    additional_rent,
+                )?;
+            }
+            info.resize(new_size)?;
+        }
+
+        let mut data = info.try_borrow_mut_data()?;
+        data.fill(0);
+        user.try_serialize(&mut &mut data[..])?;
+
+        emit!(UserMigratedEvent {
+            user: user_key,
+            old_size: current_size as u32,
+            new_size: new_size.max(current_size) as u32,
+        });
+        Ok(())
+    }
+
     /// Pause the contract (only callable by the owner). When paused, registrations and level buys are disabled.
     pub fn pause(ctx: Context<OwnerOnly>) -> Result<()> {
         let global = &mut ctx.accounts.global_state;
@@ -442,11 +725,11 @@ pub mod x3_pepe {
 }
 
 #[derive(Accounts)]
-#[instruction(root: Pubkey, owner: Pubkey, prices: Vec<u64>)]
+#[instruction(root: Pubkey, owner: Pubkey, prices: Vec<u64>, game_id: u64)]
 pub struct Initialize<'info> {
     #[account(
         init,
-        seeds = [b"state"],
+        seeds = [b"state".as_ref(), game_seed(game_id).as_ref()],
         bump,
         payer = initializer,
         space = 8 + GlobalState::MAX_SIZE
@@ -454,45 +737,93 @@ pub struct Initialize<'info> {
     pub global_state: Account<'info, GlobalState>,
     #[account(
         init,
-        seeds = [b"user", root.as_ref()],
+        seeds = [b"user".as_ref(), game_seed(game_id).as_ref(), root.as_ref()],
         bump,
         payer = initializer,
         space = 8 + UserAccount::max_size(prices.len())
     )]
     pub root_account: Account<'info, UserAccount>,
 
-    pub token_mint: Account<'info, Mint>,
+    pub token_mint: InterfaceAccount<'info, Mint>,
     #[account(
         init_if_needed,
         payer = initializer,
         associated_token::mint = token_mint,
         associated_token::authority = global_state,
+        associated_token::token_program = token_program,
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         constraint = staking_token_account.mint == token_mint.key()
     )]
-    pub staking_token_account: Account<'info, TokenAccount>,
+    pub staking_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(mut)]
     pub initializer: Signer<'info>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub rent: Sysvar<'info, Rent>,
 }
 
+#[derive(Accounts)]
+#[instruction(root: Pubkey, owner: Pubkey, prices: Vec<u64>, game_id: u64)]
+pub struct InitializeSol<'info> {
+    #[account(
+        init,
+        seeds = [b"state".as_ref(), game_seed(game_id).as_ref()],
+        bump,
+        payer = initializer,
+        space = 8 + GlobalState::MAX_SIZE
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        init,
+        seeds = [b"user".as_ref(), game_seed(game_id).as_ref(), root.as_ref()],
+        bump,
+        payer = initializer,
+        space = 8 + UserAccount::max_size(prices.len())
+    )]
+    pub root_account: Account<'info, UserAccount>,
+    #[account(mut, seeds = [b"sol_vault", game_seed(game_id).as_ref()], bump)]
+    pub sol_vault: SystemAccount<'info>,
+    #[account(
+        constraint = staking_token_account.mint == native_mint::ID @ ContractError::InvalidTokenAccount
+    )]
+    pub staking_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub initializer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateGlobal<'info> {
+    #[account(
+        mut,
+        seeds = [b"state", global_state.game_seed().as_ref()],
+        bump = global_state.bump,
+        has_one = owner @ ContractError::Unauthorized,
+        realloc = 8 + GlobalState::MAX_SIZE,
+        realloc::payer = owner,
+        realloc::zero = true
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct UpdatePrices<'info> {
     #[account(
         mut,
-        seeds = [b"state"],
+        seeds = [b"state", global_state.game_seed().as_ref()],
         bump = global_state.bump,
         has_one = owner @ ContractError::Unauthorized
     )]
     pub global_state: Account<'info, GlobalState>,
     #[account(
         mut,
-        seeds = [b"user", global_state.root.as_ref()],
+        seeds = [b"user", global_state.game_seed().as_ref(), global_state.root.as_ref()],
         bump = root_account.bump
     )]
     pub root_account: Account<'info, UserAccount>,
@@ -503,12 +834,27 @@ pub struct UpdatePrices<'info> {
 pub struct UpdateGlobal<'info> {
     #[account(
         mut,
-        seeds = [b"state"],
+        seeds = [b"state", global_state.game_seed().as_ref()],
+        bump = global_state.bump,
+        has_one = owner @ ContractError::Unauthorized
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetStakingPool<'info> {
+    #[account(
+        mut,
+        seeds = [b"state", global_state.game_seed().as_ref()],
         bump = global_state.bump,
         has_one = owner @ ContractError::Unauthorized
     )]
     pub global_state: Account<'info, GlobalState>,
     pub owner: Signer<'info>,
+    /// CHECK: Verified to be a staking_pepe Pool for the payment mint.
+    pub staking_pool: UncheckedAccount<'info>,
+    pub staking_token_account: InterfaceAccount<'info, TokenAccount>,
 }
 
 #[derive(Accounts)]
@@ -516,96 +862,171 @@ pub struct UpdateGlobal<'info> {
 pub struct Registration<'info> {
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"state"], bump = global_state.bump)]
+    #[account(mut, seeds = [b"state", global_state.game_seed().as_ref()], bump = global_state.bump)]
     pub global_state: Account<'info, GlobalState>,
     #[account(
         init,
-        seeds = [b"user", user_key.as_ref()],
+        seeds = [b"user".as_ref(), global_state.game_seed().as_ref(), user_key.as_ref()],
         bump,
         payer = payer,
         space = 8 + UserAccount::max_size(global_state.max_level as usize)
     )]
     pub user_account: Account<'info, UserAccount>,
-    #[account(mut, seeds = [b"user", referrer_key.as_ref()], bump = referrer_account.bump)]
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), referrer_key.as_ref()], bump = referrer_account.bump)]
     pub referrer_account: Account<'info, UserAccount>,
-    #[account(mut, seeds = [b"user", global_state.root.as_ref()], bump = global_state.root_bump)]
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), global_state.root.as_ref()], bump = global_state.root_bump)]
     pub root_account: Account<'info, UserAccount>,
     #[account(mut,
         // Ensure this account belongs to payer and matches global_state.token_mint
         constraint = payer_token_account.owner == payer.key(),
         constraint = payer_token_account.mint == global_state.token_mint
     )]
-    pub payer_token_account: Account<'info, TokenAccount>,
+    pub payer_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut,
         // Vault account should match global state's recorded vault
         constraint = vault_token_account.key() == global_state.vault_token_account
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut,
         constraint = staking_token_account.key() == global_state.staking_token_account
     )]
-    pub staking_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub staking_token_account: InterfaceAccount<'info, TokenAccount>,
+    // Writable for burns from the vault
+    #[account(mut, address = global_state.token_mint @ ContractError::InvalidTokenAccount)]
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+    // Lamport vault, SOL mode only. The token accounts above are token mode only.
+    #[account(mut, seeds = [b"sol_vault", global_state.game_seed().as_ref()], bump = global_state.sol_vault_bump)]
+    pub sol_vault: Option<SystemAccount<'info>>,
+    /// CHECK: Treasury wallet or token account, required for the protocol fee and treasury burns.
+    #[account(mut, address = global_state.treasury @ ContractError::InvalidTreasury)]
+    pub treasury: Option<UncheckedAccount<'info>>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-#[instruction(user_key: Pubkey, level: u8)]
+#[instruction(user_key: Pubkey)]
 pub struct BuyNewLevel<'info> {
+    // Must be the user's wallet when paying from balance
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(mut, seeds = [b"state"], bump = global_state.bump)]
+    #[account(mut, seeds = [b"state", global_state.game_seed().as_ref()], bump = global_state.bump)]
     pub global_state: Account<'info, GlobalState>,
-    #[account(mut, seeds = [b"user", user_key.as_ref()], bump = user_account.bump)]
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), user_key.as_ref()], bump = user_account.bump)]
     pub user_account: Account<'info, UserAccount>,
-    #[account(mut, seeds = [b"user", global_state.root.as_ref()], bump = global_state.root_bump)]
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), global_state.root.as_ref()], bump = global_state.root_bump)]
     pub root_account: Account<'info, UserAccount>,
     #[account(mut,
         constraint = payer_token_account.owner == payer.key(),
         constraint = payer_token_account.mint == global_state.token_mint
     )]
-    pub payer_token_account: Account<'info, TokenAccount>,
+    pub payer_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut,
         constraint = vault_token_account.key() == global_state.vault_token_account
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut,
         constraint = staking_token_account.key() == global_state.staking_token_account
     )]
-    pub staking_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub staking_token_account: InterfaceAccount<'info, TokenAccount>,
+    // Writable for burns from the vault
+    #[account(mut, address = global_state.token_mint @ ContractError::InvalidTokenAccount)]
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+    // Lamport vault, SOL mode only. The token accounts above are token mode only.
+    #[account(mut, seeds = [b"sol_vault", global_state.game_seed().as_ref()], bump = global_state.sol_vault_bump)]
+    pub sol_vault: Option<SystemAccount<'info>>,
+    /// CHECK: Treasury wallet or token account, required for the protocol fee and treasury burns.
+    #[account(mut, address = global_state.treasury @ ContractError::InvalidTreasury)]
+    pub treasury: Option<UncheckedAccount<'info>>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(user_key: Pubkey)]
+pub struct MigrateUser<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(seeds = [b"state", global_state.game_seed().as_ref()], bump = global_state.bump)]
+    pub global_state: Account<'info, GlobalState>,
+    /// CHECK: Legacy layout, checked by discriminator and rewritten by the handler.
+    #[account(mut, owner = crate::ID, seeds = [b"user", global_state.game_seed().as_ref(), user_key.as_ref()], bump)]
+    pub user_account: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetAutoUpgrade<'info> {
+    #[account(seeds = [b"state", global_state.game_seed().as_ref()], bump = global_state.bump)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), user_wallet.key().as_ref()], bump = user_account.bump,
+        constraint = user_account.wallet == user_wallet.key() @ ContractError::UnauthorizedClaim
+    )]
+    pub user_account: Account<'info, UserAccount>,
+    pub user_wallet: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct Claim<'info> {
-    #[account(mut, seeds = [b"state"], bump = global_state.bump)]
+    #[account(mut, seeds = [b"state", global_state.game_seed().as_ref()], bump = global_state.bump)]
     pub global_state: Account<'info, GlobalState>,
-    #[account(mut, seeds = [b"user", user_wallet.key().as_ref()], bump = user_account.bump,
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), user_wallet.key().as_ref()], bump = user_account.bump,
         constraint = user_account.wallet == user_wallet.key() @ ContractError::UnauthorizedClaim
     )]
     pub user_account: Account<'info, UserAccount>,
     /// The user's wallet must sign and match the user_account.wallet field.
+    /// Writable since SOL-mode claims pay lamports to it.
+    #[account(mut)]
     pub user_wallet: Signer<'info>,
     #[account(mut,
         constraint = user_token_account.owner == user_wallet.key(),
         constraint = user_token_account.mint == global_state.token_mint
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub user_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut,
         constraint = vault_token_account.key() == global_state.vault_token_account
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = global_state.token_mint @ ContractError::InvalidTokenAccount)]
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+    // Lamport vault, SOL mode only. The token accounts above are token mode only.
+    #[account(mut, seeds = [b"sol_vault", global_state.game_seed().as_ref()], bump = global_state.sol_vault_bump)]
+    pub sol_vault: Option<SystemAccount<'info>>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct ClaimTo<'info> {
+    #[account(seeds = [b"state", global_state.game_seed().as_ref()], bump = global_state.bump,
+        constraint = !global_state.is_sol_mode() @ ContractError::TokenModeOnly
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, seeds = [b"user", global_state.game_seed().as_ref(), user_wallet.key().as_ref()], bump = user_account.bump,
+        constraint = user_account.wallet == user_wallet.key() @ ContractError::UnauthorizedClaim
+    )]
+    pub user_account: Account<'info, UserAccount>,
+    pub user_wallet: Signer<'info>,
+    #[account(mut,
+        constraint = destination_token_account.mint == global_state.token_mint @ ContractError::InvalidTokenAccount
+    )]
+    pub destination_token_account: InterfaceAccount<'info, TokenAccount>,
+    // Optional so that a SOL-mode game fails on the mode check, not on deserialization
+    #[account(mut,
+        constraint = vault_token_account.key() == global_state.vault_token_account @ ContractError::InvalidVaultAccount
+    )]
+    pub vault_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = global_state.token_mint @ ContractError::InvalidTokenAccount)]
+    pub token_mint: Option<InterfaceAccount<'info, Mint>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
 #[derive(Accounts)]
 pub struct OwnerOnly<'info> {
     #[account(
         mut,
-        seeds = [b"state"],
+        seeds = [b"state", global_state.game_seed().as_ref()],
         bump = global_state.bump,
         has_one = owner @ ContractError::Unauthorized
     )]
@@ -625,18 +1046,84 @@ pub struct GlobalState {
     pub root_bump: u8,               // Bump for root user's PDA
     pub max_level: u8,               // Number of levels configured
     pub prices: Vec<u64>,            // Price for each level (in smallest currency unit)
-    pub vault_token_account: Pubkey, // PDA for vault token account
-    pub staking_token_account: Pubkey, // PDA for staking token account
+    pub vault_token_account: Pubkey, // PDA for vault token account (SOL vault PDA in SOL mode)
+    pub staking_token_account: Pubkey, // PDA for staking token account (wrapped SOL in SOL mode)
+    pub staking_pool: Pubkey,          // staking_pepe pool locked as destination (default if none)
+    pub sol_vault_bump: u8,            // Bump for the sol_vault PDA (SOL mode only)
+    pub game_id: u64,                  // Matrix instance, part of every PDA seed of the game
+    pub matrix_types: Vec<MatrixType>, // Matrix layout per level, X3 if not set
+    pub slot_shares: Vec<Vec<SlotShare>>, // Paying places per level and their shares, default if empty
+    pub burn_bps: u64,                 // Share of a released freeze that is burned
+    pub burn_destination: BurnDestination, // Where burned amounts go
+    pub treasury: Pubkey,              // Treasury wallet (SOL mode) or token account
+    pub protocol_fee_bps: u64,         // Share of each level price paid to the treasury
+    pub staking_pool_unlock_time: i64, // When a requested staking pool unlock takes effect, 0 if none
+    pub version: u8,                   // Layout version, 0 for accounts from before versioning
 }
 
 impl GlobalState {
     // Maximum space needed for GlobalState (for allocation).
     // Assuming a reasonable upper bound for max_level to avoid overly large account.
-    pub const MAX_SIZE: usize = 32 + 32 + 32 + 1 + 1 + 1 + 1 +  4 + (14 * 64) + 32 + 32;
+    pub const MAX_SIZE: usize = 32 + 32 + 32 + 1 + 1 + 1 + 1 +  4 + (14 * 64) + 32 + 32 + 32 + 1 + 8 + 4 + MAX_LEVELS
+        + 4 + MAX_LEVELS * (4 + MAX_SLOTS * (8 + 8))
+        + 8 + 1 + 32 + 8 + 8 + 1;
+
+    pub fn game_seed(&self) -> Vec<u8> {
+        game_seed(self.game_id)
+    }
+
+    pub fn matrix_type(&self, level: u8) -> MatrixType {
+        self.matrix_types
+            .get(level as usize)
+            .copied()
+            .unwrap_or(MatrixType::X3)
+    }
+
+    // Shares of the paying places of a level's cycle. The default last place freezes the
+    // full price and passes the full price up, the places before it split the remaining
+    // prices evenly into the receiver's freeze (half a price each in X3), so the shares add
+    // up as `validate_slot_shares` requires.
+    pub fn slot_shares(&self, level: u8) -> Vec<SlotShare> {
+        match self.slot_shares.get(level as usize) {
+            Some(slots) if !slots.is_empty() => slots.clone(),
+            _ => {
+                let places = self.matrix_type(level).cycle_size() as u64;
+                let earlier_total = (places - 2) * 10_000;
+                let mut slots: Vec<SlotShare> = (0..places - 1)
+                    .map(|place| SlotShare {
+                        // Any rounding remainder goes to the first places
+                        freeze_bps: earlier_total / (places - 1)
+                            + u64::from(place < earlier_total % (places - 1)),
+                        pass_up_bps: 0,
+                    })
+                    .collect();
+                slots.push(SlotShare {
+                    freeze_bps: 10_000,
+                    pass_up_bps: 10_000,
+                });
+                slots
+            }
+        }
+    }
+
+    pub fn is_sol_mode(&self) -> bool {
+        self.token_mint == Pubkey::default()
+    }
+
+    // Mint of the staking share: the payment mint, or wrapped SOL in SOL mode.
+    pub fn staking_mint(&self) -> Pubkey {
+        if self.is_sol_mode() {
+            native_mint::ID
+        } else {
+            self.token_mint
+        }
+    }
 }
 
 // State: Per-user account
-#[account]
+// The discriminator, sha256("account:UserAccountV2")[..8], tells the current layout apart from
+// accounts written before `X3.first_line` and `auto_upgrade`, which need `migrate_user`.
+#[account(discriminator = [49, 229, 99, 247, 99, 241, 117, 153])]
 #[derive(Debug)]
 pub struct UserAccount {
     pub wallet: Pubkey,               // The user's wallet address
@@ -645,6 +1132,7 @@ pub struct UserAccount {
     pub matrix: Vec<X3>,              // X3 matrix data for each level active
     pub closed_cycles: Vec<NewCycle>, // Boosters for the user (max 10 initially)
     pub bump: u8,                     // PDA bump for this account
+    pub auto_upgrade: bool,           // Lets anyone buy the next blocked level from the balance
 }
 
 impl UserAccount {
@@ -655,24 +1143,76 @@ impl UserAccount {
     
     // Compute max space for a UserAccount with custom booster capacity
     pub fn max_size_with_boosters(max_level: usize, booster_capacity: usize) -> usize {
-        // wallet (32) + referrer (32) + balance (8) + bump (1) +
+        // wallet (32) + referrer (32) + balance (8) + bump (1) + auto_upgrade (1) +
         // matrix vector: 4-byte length + each element X3 size.
-        // X3 struct = 1 (bool) + 1 (u8) + 1 (u8) + 8 (u64) + 8 (u64) + 8 (u64) = 27 bytes per level.
+        // X3 struct = 1 (bool) + 1 (u8) + 1 (u8) + 8 (u64) + 8 (u64) + 8 (u64) + 1 (u8) = 28 bytes per level.
         // boosters vector: 4-byte length + each element Booster size.
         // Booster struct = 8 (u64) + 1 (u8) = 9 bytes per booster.
-        32 + 32 + 8 + 1 + 4 + (max_level * 27) + 4 + (booster_capacity * 9)
+        32 + 32 + 8 + 1 + 1 + 4 + (max_level * 28) + 4 + (booster_capacity * 9)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatrixType {
+    // Three places on one line, the third closes the cycle.
+    X3,
+    // Two places on the first line spilling over to the upline's second line, four places on
+    // the second line, the fourth closes the cycle.
+    X4,
+}
+
+impl MatrixType {
+    // Places on the line that pays the receiver and closes the cycle.
+    fn cycle_size(self) -> u8 {
+        match self {
+            MatrixType::X3 => 3,
+            MatrixType::X4 => 4,
+        }
     }
+
+    // Places before that line, used to number places in events.
+    fn first_place(self) -> u8 {
+        match self {
+            MatrixType::X3 => 1,
+            MatrixType::X4 => X4_FIRST_LINE + 1,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BurnDestination {
+    // Forwarded to `staking_token_account` with the staking share.
+    Staking,
+    // Burned from the vault, reducing the mint supply.
+    Burn,
+    // Paid to `treasury`.
+    Treasury,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaymentSource {
+    // Full price from `payer_token_account`, or the payer's lamports in SOL mode.
+    Wallet,
+    // `UserAccount.balance` first, the shortfall from the wallet.
+    Balance,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct SlotShare {
+    pub freeze_bps: u64,  // Share of the price frozen for the receiver until the cycle closes
+    pub pass_up_bps: u64, // Share of the price paid to the receiver's first active upline
 }
 
 /// X3 matrix entry for a level
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
 pub struct X3 {
     pub blocked: bool, // If the user is blocked on this level (did not buy next level after cycle)
-    pub referrals: u8, // Number of referrals in the current cycle (0 to 3, resets to 1 on cycle)
+    pub referrals: u8, // Number of referrals in the current cycle (0 to 3, resets to 1 on cycle); X4: second line (0 to 4)
     pub cycles: u8,    // Number of times this level has cycled for the user
     pub freeze: u64,   // Amount currently frozen at this level for the user
     pub level_bought_time: u64,
     pub close_level_time: u64,
+    pub first_line: u8, // X4 only: places taken in the first line of the current cycle (0 to 2)
 }
 
 /// Booster entry for a user
@@ -682,6 +1222,55 @@ pub struct NewCycle {
     pub level: u8,         // Level of the boost
 }
 
+/// `UserAccount` as written under the original `account:UserAccount` discriminator.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct LegacyUserAccount {
+    pub wallet: Pubkey,
+    pub referrer: Pubkey,
+    pub balance: u64,
+    pub matrix: Vec<LegacyX3>,
+    pub closed_cycles: Vec<NewCycle>,
+    pub bump: u8,
+}
+
+/// `X3` before `first_line`: 27 bytes per level.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct LegacyX3 {
+    pub blocked: bool,
+    pub referrals: u8,
+    pub cycles: u8,
+    pub freeze: u64,
+    pub level_bought_time: u64,
+    pub close_level_time: u64,
+}
+
+impl From<LegacyUserAccount> for UserAccount {
+    fn from(legacy: LegacyUserAccount) -> Self {
+        UserAccount {
+            wallet: legacy.wallet,
+            referrer: legacy.referrer,
+            balance: legacy.balance,
+            // Every legacy level is X3, which does not use the first line
+            matrix: legacy
+                .matrix
+                .into_iter()
+                .map(|x3| X3 {
+                    blocked: x3.blocked,
+                    referrals: x3.referrals,
+                    cycles: x3.cycles,
+                    freeze: x3.freeze,
+                    level_bought_time: x3.level_bought_time,
+                    close_level_time: x3.close_level_time,
+                    first_line: 0,
+                })
+                .collect(),
+            closed_cycles: legacy.closed_cycles,
+            bump: legacy.bump,
+            auto_upgrade: false,
+        }
+    }
+}
+
 #[event]
 pub struct RegistrationEvent {
     pub user: Pubkey,
@@ -729,6 +1318,12 @@ pub struct ClaimedEvent {
     pub amount: u64,
 }
 #[event]
+pub struct ClaimedToEvent {
+    pub user: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+}
+#[event]
 pub struct FrozenEvent {
     pub user: Pubkey,
     pub level: u8,
@@ -741,8 +1336,22 @@ pub struct UnfrozenEvent {
     pub amount: u64,
 }
 #[event]
+pub struct PaidFromBalanceEvent {
+    pub user: Pubkey,
+    pub level: u8,
+    pub amount: u64,
+}
+#[event]
+pub struct ProtocolFeeEvent {
+    pub user: Pubkey,
+    pub level: u8,
+    pub amount: u64,
+    pub treasury: Pubkey,
+}
+#[event]
 pub struct BurnedEvent {
     pub amount: u64,
+    pub destination: BurnDestination,
 }
 #[event]
 pub struct BoosterCreatedEvent {
@@ -752,12 +1361,248 @@ pub struct BoosterCreatedEvent {
     pub created_time: u64,
 }
 #[event]
+pub struct StakingPoolSetEvent {
+    pub staking_pool: Pubkey,
+    pub staking_token_account: Pubkey,
+}
+#[event]
+pub struct StakingPoolUnlockRequestedEvent {
+    pub staking_pool: Pubkey,
+    pub unlock_time: i64,
+}
+#[event]
+pub struct UserMigratedEvent {
+    pub user: Pubkey,
+    pub old_size: u32,
+    pub new_size: u32,
+}
+#[event]
 pub struct AccountResizedEvent {
     pub user: Pubkey,
     pub old_size: u32,
     pub new_size: u32,
 }
 
+// Transfer fee a Token-2022 mint charges on top of `net` so that `net` arrives,
+// 0 for mints without the transfer fee extension.
+fn inverse_transfer_fee(mint: &AccountInfo, net: u64) -> Result<u64> {
+    let data = mint.try_borrow_data()?;
+    let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    match mint.get_extension::<TransferFeeConfig>() {
+        Ok(config) => config
+            .calculate_inverse_epoch_fee(Clock::get()?.epoch, net)
+            .ok_or_else(|| error!(ContractError::Overflow)),
+        Err(_) => Ok(0),
+    }
+}
+
+/// PDA seed of a game, inserted after the `state`, `user` and `sol_vault` prefixes.
+/// Empty for game 0 so its addresses match the original single-matrix layout.
+pub fn game_seed(game_id: u64) -> Vec<u8> {
+    if game_id == 0 {
+        Vec::new()
+    } else {
+        game_id.to_le_bytes().to_vec()
+    }
+}
+
+fn init_root_account(
+    root_acct: &mut UserAccount,
+    root: Pubkey,
+    max_level: u8,
+    bump: u8,
+) -> Result<()> {
+    root_acct.wallet = root;
+    root_acct.referrer = Pubkey::default(); // root has no referrer (treated as top)
+    root_acct.balance = 0;
+    root_acct.matrix = Vec::with_capacity(max_level as usize);
+    root_acct.closed_cycles = Vec::with_capacity(10);
+    root_acct.bump = bump;
+    root_acct.auto_upgrade = false;
+
+    for _ in 0..max_level {
+        root_acct.matrix.push(X3 {
+            blocked: false,
+            referrals: 0,
+            cycles: 0,
+            freeze: 0,
+            level_bought_time: Clock::get()?.unix_timestamp as u64,
+            close_level_time: 0,
+            first_line: 0,
+        });
+    }
+
+    Ok(())
+}
+
+// Where level payments are held: the sol_vault PDA in SOL mode, else the vault token account.
+struct Vault<'a, 'info> {
+    global: &'a Account<'info, GlobalState>,
+    token_account: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    token_mint: Option<&'a InterfaceAccount<'info, Mint>>,
+    sol_vault: Option<&'a SystemAccount<'info>>,
+    token_program: &'a Interface<'info, TokenInterface>,
+    system_program: &'a Program<'info, System>,
+}
+
+impl<'a, 'info> Vault<'a, 'info> {
+    // Moves `price` from the payer into the vault. With tokens the payer covers any
+    // transfer fee so the vault receives the full price.
+    fn collect(
+        &self,
+        payer: &Signer<'info>,
+        payer_token_account: Option<&InterfaceAccount<'info, TokenAccount>>,
+        price: u64,
+    ) -> Result<()> {
+        if self.global.is_sol_mode() {
+            return system_program::transfer(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: payer.to_account_info(),
+                        to: self.sol_vault()?.to_account_info(),
+                    },
+                ),
+                price,
+            );
+        }
+
+        let (vault_token_account, token_mint) = self.token_accounts()?;
+        let payer_token_account =
+            payer_token_account.ok_or(ContractError::MissingPaymentAccount)?;
+        let payment = price
+            .checked_add(inverse_transfer_fee(&token_mint.to_account_info(), price)?)
+            .ok_or(ContractError::Overflow)?;
+        token_interface::transfer_checked(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                TransferChecked {
+                    from: payer_token_account.to_account_info(),
+                    mint: token_mint.to_account_info(),
+                    to: vault_token_account.to_account_info(),
+                    authority: payer.to_account_info(),
+                },
+            ),
+            payment,
+            token_mint.decimals,
+        )
+    }
+
+    // Pays `amount` out of the vault to a wallet in SOL mode, else to a token account of the
+    // payment mint. Any transfer fee comes out of what `to` receives.
+    fn pay_out(&self, to: AccountInfo<'info>, amount: u64) -> Result<()> {
+        if self.global.is_sol_mode() {
+            let game_seed = self.global.game_seed();
+            let seeds = &[b"sol_vault".as_ref(), game_seed.as_ref(), &[self.global.sol_vault_bump]];
+            return system_program::transfer(
+                CpiContext::new_with_signer(
+                    self.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: self.sol_vault()?.to_account_info(),
+                        to,
+                    },
+                    &[seeds],
+                ),
+                amount,
+            );
+        }
+
+        let (vault_token_account, token_mint) = self.token_accounts()?;
+        let game_seed = self.global.game_seed();
+        let seeds = &[b"state".as_ref(), game_seed.as_ref(), &[self.global.bump]];
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                TransferChecked {
+                    from: vault_token_account.to_account_info(),
+                    mint: token_mint.to_account_info(),
+                    to,
+                    authority: self.global.to_account_info(),
+                },
+                &[seeds],
+            ),
+            amount,
+            token_mint.decimals,
+        )
+    }
+
+    // Forwards the staking share. In SOL mode the lamports are wrapped in place by
+    // syncing the wrapped-SOL staking account.
+    fn send_to_staking(
+        &self,
+        staking_token_account: &InterfaceAccount<'info, TokenAccount>,
+        amount: u64,
+    ) -> Result<()> {
+        self.pay_out(staking_token_account.to_account_info(), amount)?;
+        if self.global.is_sol_mode() {
+            token_interface::sync_native(CpiContext::new(
+                self.token_program.to_account_info(),
+                SyncNative {
+                    account: staking_token_account.to_account_info(),
+                },
+            ))?;
+        }
+        Ok(())
+    }
+
+    // Forwards a placement's staking share and disposes of the burned amount according to
+    // `burn_destination`.
+    fn settle(
+        &self,
+        payout: &MatrixPayout,
+        staking_token_account: &InterfaceAccount<'info, TokenAccount>,
+        treasury: Option<&UncheckedAccount<'info>>,
+    ) -> Result<()> {
+        let mut to_staking = payout.to_staking;
+        if payout.burned > 0 {
+            match self.global.burn_destination {
+                BurnDestination::Staking => to_staking += payout.burned,
+                BurnDestination::Burn => self.burn(payout.burned)?,
+                BurnDestination::Treasury => {
+                    let treasury = treasury.ok_or(ContractError::InvalidTreasury)?;
+                    self.pay_out(treasury.to_account_info(), payout.burned)?;
+                }
+            }
+        }
+        if to_staking != 0 {
+            self.send_to_staking(staking_token_account, to_staking)?;
+        }
+        Ok(())
+    }
+
+    fn burn(&self, amount: u64) -> Result<()> {
+        let (vault_token_account, token_mint) = self.token_accounts()?;
+        let game_seed = self.global.game_seed();
+        let seeds = &[b"state".as_ref(), game_seed.as_ref(), &[self.global.bump]];
+        token_interface::burn(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                Burn {
+                    mint: token_mint.to_account_info(),
+                    from: vault_token_account.to_account_info(),
+                    authority: self.global.to_account_info(),
+                },
+                &[seeds],
+            ),
+            amount,
+        )
+    }
+
+    fn sol_vault(&self) -> Result<&'a SystemAccount<'info>> {
+        self.sol_vault
+            .ok_or_else(|| error!(ContractError::MissingPaymentAccount))
+    }
+
+    fn token_accounts(
+        &self,
+    ) -> Result<(&'a InterfaceAccount<'info, TokenAccount>, &'a InterfaceAccount<'info, Mint>)> {
+        match (self.token_account, self.token_mint) {
+            (Some(token_account), Some(token_mint)) => Ok((token_account, token_mint)),
+            _ => err!(ContractError::MissingPaymentAccount),
+        }
+    }
+}
+
 fn is_user_exists(user_account: &UserAccount, root_wallet: Pubkey) -> bool {
     // A user exists if their referrer is non-zero or if they are the root user
     user_account.referrer != Pubkey::default() || user_account.wallet == root_wallet
@@ -780,16 +1625,18 @@ where
     'link: 'info,
 {
     let lvl = level as usize;
+    let matrix_type = global.matrix_type(level);
     receiver_acct.matrix[lvl].referrals += 1;
-    let place = receiver_acct.matrix[lvl].referrals;
+    let place = receiver_acct.matrix[lvl].referrals + matrix_type.first_place() - 1;
     emit!(NewUserPlaceEvent {
         user: receiver_acct.wallet,
         caller,
         level,
         place,
     });
-    if receiver_acct.matrix[lvl].referrals >= 3 {
+    if receiver_acct.matrix[lvl].referrals as usize >= global.slot_shares(level).len() {
         receiver_acct.matrix[lvl].referrals = 0;
+        receiver_acct.matrix[lvl].first_line = 0;
         receiver_acct.matrix[lvl].cycles += 1;
 
         if receiver_acct.matrix[lvl].cycles == 1 {
@@ -832,52 +1679,305 @@ where
         if level < global.max_level - 1 && !is_level_active(receiver_acct, level + 1) {
             receiver_acct.matrix[lvl].blocked = true;
         }
-        return Ok(unfreeze(receiver_acct, level))
+        return unfreeze(receiver_acct, level, global)
     }
 
     Ok(None)
 }
 
-fn distribute_dividends(
-    user_account: &mut UserAccount,
-    receiver_acct: &mut UserAccount,
-    global: &GlobalState,
+// Activates `level` for `user_key`: takes the payment, then places the user in the level's
+// matrix. Shared by the level purchase instructions, which check who may pay.
+fn buy_level<'link, 'info>(
+    accounts: &mut BuyNewLevel<'info>,
+    program_id: &Pubkey,
+    remaining_accounts: &'link [AccountInfo<'info>],
     user_key: Pubkey,
     level: u8,
-    price: u64,
-    remaining_accounts: &[AccountInfo],
-) -> Result<Option<u64>> {
-    let mut return_value: u64 = 0;
-    if user_account.referrer != receiver_acct.wallet {
-        let extra = price / 2;
+    source: PaymentSource,
+) -> Result<()>
+where
+    'link: 'info,
+{
+    let global = &accounts.global_state;
+    require!(!global.paused, ContractError::ContractPaused);
+    require!(
+        global.version == GLOBAL_STATE_VERSION,
+        ContractError::MigrationRequired
+    );
+
+    let payer = &accounts.payer;
+    let user_account = &mut accounts.user_account;
+    let root_acct = &mut accounts.root_account;
+    require!(
+        is_user_exists(user_account, global.root),
+        ContractError::UserNotExists
+    );
+    require!(level < global.max_level, ContractError::LevelOutOfRange);
+    require!(
+        !is_level_active(user_account, level),
+        ContractError::LevelAlreadyActive
+    );
+    require!(
+        level == 0 || is_level_active(user_account, level - 1),
+        ContractError::PreviousLevelRequired
+    );
+
+    if level > 0 && user_account.matrix[(level - 1) as usize].blocked {
+        user_account.matrix[(level - 1) as usize].blocked = false;
+    }
+    user_account.matrix.push(X3 {
+        blocked: false,
+        referrals: 0,
+        cycles: 0,
+        freeze: 0,
+        level_bought_time: Clock::get()?.unix_timestamp as u64,
+        close_level_time: 0,
+        first_line: 0,
+    });
+
+    // Payment for the level
+    let price = global.prices[level as usize];
+
+    let vault = Vault {
+        global,
+        token_account: accounts.vault_token_account.as_ref(),
+        token_mint: accounts.token_mint.as_ref(),
+        sol_vault: accounts.sol_vault.as_ref(),
+        token_program: &accounts.token_program,
+        system_program: &accounts.system_program,
+    };
+    // The balance already sits in the vault, only the shortfall is pulled from the payer
+    let from_balance = match source {
+        PaymentSource::Wallet => 0,
+        PaymentSource::Balance => user_account.balance.min(price),
+    };
+    if from_balance > 0 {
+        user_account.balance -= from_balance;
+        emit!(PaidFromBalanceEvent {
+            user: user_key,
+            level,
+            amount: from_balance,
+        });
+    }
+    if price > from_balance {
+        vault.collect(payer, accounts.payer_token_account.as_ref(), price - from_balance)?;
+    }
+
+    // The protocol fee comes off the top, the matrix splits the rest
+    let protocol_fee = share_of_price(price, global.protocol_fee_bps)?;
+    if protocol_fee > 0 {
+        let treasury = accounts.treasury.as_ref().ok_or(ContractError::InvalidTreasury)?;
+        vault.pay_out(treasury.to_account_info(), protocol_fee)?;
+        emit!(ProtocolFeeEvent {
+            user: user_key,
+            level,
+            amount: protocol_fee,
+            treasury: treasury.key(),
+        });
+    }
+    let split_price = price - protocol_fee;
+
+    let mut receiver_acct = find_receiver_account(
+        user_account,
+        user_key,
+        level,
+        &global.game_seed(),
+        program_id,
+        remaining_accounts,
+    )?;
 
-        // Only add to balance if receiver is not root
-        if receiver_acct.wallet != global.root {
-            receiver_acct.balance = receiver_acct.balance.checked_add(extra).unwrap();
-            return_value += extra;
+    let matrix = MatrixContext {
+        global,
+        payer: accounts.payer.to_account_info(),
+        system_program: accounts.system_program.to_account_info(),
+        program_id,
+        remaining_accounts,
+    };
+    let (payout, upline_acct) = place_in_matrix(
+        user_account,
+        &mut receiver_acct,
+        user_key,
+        level,
+        split_price,
+        &matrix,
+    )?;
+
+    vault.settle(
+        &payout,
+        &accounts.staking_token_account,
+        accounts.treasury.as_ref(),
+    )?;
+
+    emit!(UpgradeEvent {
+        user: user_key,
+        level,
+        amount: price,
+    });
+
+    if root_acct.key() == receiver_acct.key() {
+        accounts.root_account = receiver_acct;
+    } else {
+        receiver_acct.exit(program_id)?;
+    }
+
+    // An X4 spillover also updated the receiver's upline
+    if let Some(upline_acct) = upline_acct {
+        if accounts.root_account.key() == upline_acct.key() {
+            accounts.root_account = upline_acct;
         } else {
-            // If receiver is root, return all dividends (base + extra)
-            return_value += price;
+            upline_acct.exit(program_id)?;
         }
+    }
+
+    Ok(())
+}
+
+// Accounts the matrix engine needs besides the buyer and the receiver.
+struct MatrixContext<'a, 'info> {
+    global: &'a GlobalState,
+    payer: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    program_id: &'a Pubkey,
+    remaining_accounts: &'info [AccountInfo<'info>],
+}
+
+// Amounts a placement leaves in the vault for the protocol.
+#[derive(Default)]
+struct MatrixPayout {
+    to_staking: u64,
+    burned: u64,
+}
+
+// Places the buyer under `receiver_acct` according to the level's matrix type and pays for
+// the place. Returns what the vault has to forward and, after an X4 spillover, the
+// receiver's upline, which the caller has to write back.
+fn place_in_matrix<'info>(
+    user_account: &mut UserAccount,
+    receiver_acct: &mut Account<'info, UserAccount>,
+    user_key: Pubkey,
+    level: u8,
+    price: u64,
+    matrix: &MatrixContext<'_, 'info>,
+) -> Result<(MatrixPayout, Option<Account<'info, UserAccount>>)> {
+    let global = matrix.global;
+    // The referrer missed the place, the receiver is an upline further up
+    if receiver_acct.wallet != user_account.referrer {
+        let to_staking = send_extra_dividends(receiver_acct, global, user_key, level, price);
+        return Ok((MatrixPayout { to_staking, burned: 0 }, None));
+    }
 
-        emit!(SentDividendsEvent {
-            receiver: receiver_acct.wallet,
-            from: user_key,
+    let lvl = level as usize;
+    if global.matrix_type(level) == MatrixType::X4
+        && receiver_acct.wallet != global.root
+        && receiver_acct.matrix[lvl].first_line < X4_FIRST_LINE
+    {
+        receiver_acct.matrix[lvl].first_line += 1;
+        emit!(NewUserPlaceEvent {
+            user: receiver_acct.wallet,
+            caller: user_key,
             level,
-            amount: return_value,
-            mode: 1, // extra
+            place: receiver_acct.matrix[lvl].first_line,
         });
-        return Ok(Some(return_value));
+
+        // A first line place is also a second line place of the receiver's upline, who is paid
+        let mut upline_acct = find_receiver_account(
+            receiver_acct,
+            user_key,
+            level,
+            &global.game_seed(),
+            matrix.program_id,
+            matrix.remaining_accounts,
+        )?;
+        let payout = fill_place(user_account, &mut upline_acct, user_key, level, price, matrix)?;
+        return Ok((payout, Some(upline_acct)));
+    }
+
+    let payout = fill_place(user_account, receiver_acct, user_key, level, price, matrix)?;
+    Ok((payout, None))
+}
+
+// Takes the next paying place in `receiver_acct`'s matrix and distributes the payment.
+fn fill_place<'info>(
+    user_account: &mut UserAccount,
+    receiver_acct: &mut Account<'info, UserAccount>,
+    user_key: Pubkey,
+    level: u8,
+    price: u64,
+    matrix: &MatrixContext<'_, 'info>,
+) -> Result<MatrixPayout> {
+    let receiver_account_info = receiver_acct.to_account_info();
+    let burned = update_matrix(
+        receiver_acct,
+        receiver_account_info,
+        user_key,
+        level,
+        matrix.global,
+        matrix.payer.clone(),
+        matrix.system_program.clone(),
+    )?.unwrap_or_default();
+    let to_staking = distribute_dividends(
+        user_account,
+        receiver_acct,
+        matrix.global,
+        user_key,
+        level,
+        price,
+        matrix.remaining_accounts,
+    )?.unwrap_or_default();
+    Ok(MatrixPayout { to_staking, burned })
+}
+
+// Pays half the price to an upline that received a place its inactive referrer missed.
+fn send_extra_dividends(
+    receiver_acct: &mut UserAccount,
+    global: &GlobalState,
+    user_key: Pubkey,
+    level: u8,
+    price: u64,
+) -> u64 {
+    let mut return_value: u64 = 0;
+    let extra = price / 2;
+
+    // Only add to balance if receiver is not root
+    if receiver_acct.wallet != global.root {
+        receiver_acct.balance = receiver_acct.balance.checked_add(extra).unwrap();
+        return_value += extra;
+    } else {
+        // If receiver is root, return all dividends (base + extra)
+        return_value += price;
     }
 
+    emit!(SentDividendsEvent {
+        receiver: receiver_acct.wallet,
+        from: user_key,
+        level,
+        amount: return_value,
+        mode: 1, // extra
+    });
+    return_value
+}
+
+fn distribute_dividends(
+    user_account: &mut UserAccount,
+    receiver_acct: &mut UserAccount,
+    global: &GlobalState,
+    user_key: Pubkey,
+    level: u8,
+    price: u64,
+    remaining_accounts: &[AccountInfo],
+) -> Result<Option<u64>> {
+    let mut return_value: u64 = 0;
     let lvl = level as usize;
-    let recv_ref_count = receiver_acct.matrix[lvl].referrals;
-    let mut dividends: u64 = 0;
+    let slot_shares = global.slot_shares(level);
+    // A count of 0 means this place was the last one and closed the cycle
+    let slot = match receiver_acct.matrix[lvl].referrals {
+        0 => slot_shares.len() - 1,
+        count => (count as usize - 1).min(slot_shares.len() - 1),
+    };
+    let dividends = share_of_price(price, slot_shares[slot].freeze_bps)?;
+    let pass_up = share_of_price(price, slot_shares[slot].pass_up_bps)?;
 
-    if recv_ref_count == 1 || recv_ref_count == 2{
-        dividends = price / 2;
-    } else if recv_ref_count == 0 {
-        dividends = price;
+    if pass_up > 0 {
         let mut current_wallet = receiver_acct.referrer;
         if current_wallet == Pubkey::default() {
             current_wallet = global.root;
@@ -885,14 +1985,14 @@ fn distribute_dividends(
 
         loop {
             if current_wallet == global.root {
-                return_value += dividends;
+                return_value += pass_up;
                 break;
             }
             
             let current_info = remaining_accounts.iter().find(|info| {
                 info.key
                     == &Pubkey::find_program_address(
-                        &[b"user", current_wallet.as_ref()],
+                        &[b"user", global.game_seed().as_ref(), current_wallet.as_ref()],
                         &ID,
                     )
                     .0
@@ -904,7 +2004,7 @@ fn distribute_dividends(
             };
             
             if is_level_active(&current_user, level) {
-                current_user.balance = current_user.balance.checked_add(dividends).unwrap();
+                current_user.balance = current_user.balance.checked_add(pass_up).unwrap();
                 let mut dst = current_info.try_borrow_mut_data()?;
                 current_user.try_serialize(&mut *dst)?;
 
@@ -912,7 +2012,7 @@ fn distribute_dividends(
                     receiver: current_user.wallet,
                     from: user_key,
                     level,
-                    amount: dividends,
+                    amount: pass_up,
                     mode: 1, // extra
                 });
                 break;
@@ -959,12 +2059,39 @@ fn distribute_dividends(
     }
 }
 
-fn unfreeze(user: &mut UserAccount, level: u8) -> Option<u64> {
+// Checks a level's paying places: at most MAX_SLOTS, each share at most the price, and
+// over a cycle the shares add up to the price of every place.
+pub fn validate_slot_shares(slots: &[SlotShare]) -> Result<()> {
+    require!(
+        !slots.is_empty() && slots.len() <= MAX_SLOTS,
+        ContractError::InvalidSlotShares
+    );
+    let mut total: u64 = 0;
+    for slot in slots.iter() {
+        require!(
+            slot.freeze_bps <= 10_000 && slot.pass_up_bps <= 10_000,
+            ContractError::InvalidSlotShares
+        );
+        total += slot.freeze_bps + slot.pass_up_bps;
+    }
+    require!(
+        total == slots.len() as u64 * 10_000,
+        ContractError::InvalidSlotShares
+    );
+    Ok(())
+}
+
+fn share_of_price(price: u64, bps: u64) -> Result<u64> {
+    let share = price as u128 * bps as u128 / 10_000;
+    u64::try_from(share).map_err(|_| error!(ContractError::Overflow))
+}
+
+fn unfreeze(user: &mut UserAccount, level: u8, global: &GlobalState) -> Result<Option<u64>> {
     let lvl = level as usize;
     let freeze_amount = user.matrix[lvl].freeze;
     if freeze_amount > 0 {
         user.matrix[lvl].freeze = 0;
-        let burn = freeze_amount / 200; // 0.5% burn
+        let burn = share_of_price(freeze_amount, global.burn_bps)?;
         let release = freeze_amount - burn;
         user.balance = user.balance.checked_add(release).unwrap();
         emit!(UnfrozenEvent {
@@ -972,16 +2099,20 @@ fn unfreeze(user: &mut UserAccount, level: u8) -> Option<u64> {
             level,
             amount: release,
         });
-        emit!(BurnedEvent { amount: burn });
-        return Some(burn);
+        emit!(BurnedEvent {
+            amount: burn,
+            destination: global.burn_destination,
+        });
+        return Ok(Some(burn));
     }
-    None
+    Ok(None)
 }
 
 fn find_receiver_account<'info, 'link>(
     user_account: &Account<UserAccount>,
     user_key: Pubkey,
     level: u8,
+    game_seed: &[u8],
     ctx_program_id: &Pubkey,
     remaining_accounts: &'link [AccountInfo<'info>],
 ) -> Result<Account<'info, UserAccount>>
@@ -994,7 +2125,7 @@ where
         .find(|info| {
             info.key
                 == &Pubkey::find_program_address(
-                    &[b"user", next_upline_wallet.as_ref()],
+                    &[b"user", game_seed, next_upline_wallet.as_ref()],
                     ctx_program_id,
                 )
                 .0
@@ -1026,11 +2157,36 @@ where
         &next_user,
         user_key,
         level,
+        game_seed,
         ctx_program_id,
         remaining_accounts,
     )
 }
 
+// Checks the account is a staking_pepe `Pool` staking `token_mint`, read at
+// `STAKING_POOL_MINT_OFFSET`.
+fn verify_staking_pool(staking_pool: &AccountInfo, token_mint: Pubkey) -> Result<()> {
+    require_keys_eq!(
+        *staking_pool.owner,
+        STAKING_PROGRAM_ID,
+        ContractError::InvalidStakingPool
+    );
+    let data = staking_pool.try_borrow_data()?;
+    require!(
+        data.len() >= STAKING_POOL_MINT_OFFSET + 32,
+        ContractError::InvalidStakingPool
+    );
+    require!(
+        data[..8] == hash(b"account:Pool").to_bytes()[..8],
+        ContractError::InvalidStakingPool
+    );
+    require!(
+        data[STAKING_POOL_MINT_OFFSET..STAKING_POOL_MINT_OFFSET + 32] == token_mint.to_bytes(),
+        ContractError::InvalidStakingPool
+    );
+    Ok(())
+}
+
 fn resize_user_account_for_boosters<'info>(
     user_account: &mut UserAccount,
     user_account_info: AccountInfo<'info>,
@@ -1123,4 +2279,32 @@ pub enum ContractError {
     InvalidResizeCapacity,
     #[msg("Excessive booster capacity - maximum 100 boosters allowed")]
     ExcessiveBoosterCapacity,
+    #[msg("Amount exceeds the claimable balance")]
+    InsufficientBalance,
+    #[msg("Account is not a staking pool vault for the payment mint")]
+    InvalidStakingPool,
+    #[msg("Staking account is locked to a staking pool, clear it and wait for the delay first")]
+    StakingPoolLocked,
+    #[msg("Vault or payment account for the payment mode not provided")]
+    MissingPaymentAccount,
+    #[msg("Slot shares must be at most 10000 bps each and add up to 10000 bps per slot")]
+    InvalidSlotShares,
+    #[msg("Invalid burn rate or destination")]
+    InvalidBurnConfig,
+    #[msg("Treasury account missing or does not match")]
+    InvalidTreasury,
+    #[msg("Protocol fee above 10000 bps or no treasury set")]
+    InvalidProtocolFee,
+    #[msg("Auto-upgrade is not enabled for this user")]
+    AutoUpgradeDisabled,
+    #[msg("Highest level is not blocked or the balance does not cover the next level")]
+    AutoUpgradeNotReady,
+    #[msg("Only available for token-denominated games")]
+    TokenModeOnly,
+    #[msg("No staking pool lock to clear")]
+    StakingPoolNotLocked,
+    #[msg("Account is already in the current layout")]
+    AlreadyMigrated,
+    #[msg("Global state must be migrated first")]
+    MigrationRequired,
 }
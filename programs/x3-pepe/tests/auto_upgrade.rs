@@ -0,0 +1,64 @@
+mod common;
+
+use anchor_lang::solana_program::pubkey::Pubkey;
+use common::*;
+use x3_pepe::ContractError;
+
+const PRICE: u64 = 1_000_000;
+
+fn blocked_user(env: &mut Env, game: &mut Game, balance: u64, auto_upgrade: bool) -> Pubkey {
+    let root = game.root;
+    let user = game.register(env, &root);
+    game.update_user(env, &user, |account| {
+        account.matrix[0].blocked = true;
+        account.balance = balance;
+        account.auto_upgrade = auto_upgrade;
+    });
+    user
+}
+
+// Anyone can crank an opted-in user's blocked level, the next level is paid from the balance
+#[test]
+fn auto_upgrade_buys_next_level_from_balance() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE, 2 * PRICE, 4 * PRICE], Vec::new());
+    let user = blocked_user(&mut env, &mut game, 3 * PRICE, true);
+    let cranker = Pubkey::new_unique();
+    env.fund(cranker);
+    let wallet_before = env.lamports(&user);
+
+    game.auto_upgrade(&mut env, &cranker, &user).unwrap();
+    let account = game.user(&env, &user);
+    assert_eq!(account.matrix.len(), 2);
+    assert!(!account.matrix[0].blocked);
+    assert!(!account.matrix[1].blocked);
+    assert_eq!(account.balance, PRICE);
+    assert_eq!(env.lamports(&user), wallet_before);
+
+    // Level 1 is not blocked, so there is nothing to upgrade
+    assert_eq!(
+        game.auto_upgrade(&mut env, &cranker, &user),
+        Err(error_code(ContractError::AutoUpgradeNotReady as u32))
+    );
+}
+
+#[test]
+fn auto_upgrade_requires_opt_in_and_balance() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE, 2 * PRICE], Vec::new());
+    let cranker = Pubkey::new_unique();
+    env.fund(cranker);
+
+    let disabled = blocked_user(&mut env, &mut game, 2 * PRICE, false);
+    assert_eq!(
+        game.auto_upgrade(&mut env, &cranker, &disabled),
+        Err(error_code(ContractError::AutoUpgradeDisabled as u32))
+    );
+
+    let short = blocked_user(&mut env, &mut game, 2 * PRICE - 1, true);
+    assert_eq!(
+        game.auto_upgrade(&mut env, &cranker, &short),
+        Err(error_code(ContractError::AutoUpgradeNotReady as u32))
+    );
+    assert_eq!(game.user(&env, &short).matrix.len(), 1);
+}
@@ -0,0 +1,532 @@
+// In-process harness: runs instructions through `x3_pepe::entry` with the syscalls the
+// program needs stubbed. System program CPIs move lamports and create accounts; token program
+// CPIs succeed without moving tokens, so token balances are not tracked.
+#![allow(dead_code, deprecated)]
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Once;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{
+    bpf_loader_upgradeable,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    instruction::Instruction,
+    program_error::ProgramError,
+    program_stubs::{self, SyscallStubs},
+    system_program, sysvar,
+};
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_spl::token::spl_token::{
+    self,
+    solana_program::program_pack::Pack,
+    state::{Account as SplAccount, AccountState},
+};
+use x3_pepe::{GlobalState, UserAccount, X3, BurnDestination, MatrixType, PaymentSource};
+
+pub const START_TIME: i64 = 1_700_000_000;
+pub const WALLET_LAMPORTS: u64 = 1_000_000_000_000;
+
+thread_local! {
+    static NOW: Cell<i64> = const { Cell::new(START_TIME) };
+}
+
+struct Stubs;
+
+impl SyscallStubs for Stubs {
+    fn sol_log(&self, _message: &str) {}
+    fn sol_log_data(&self, _data: &[&[u8]]) {}
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = Clock {
+            unix_timestamp: NOW.with(|now| now.get()),
+            ..Clock::default()
+        };
+        unsafe { *(var_addr as *mut Clock) = clock };
+        0
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        0
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        _signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        if instruction.program_id == system_program::ID {
+            system_cpi(instruction, account_infos)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn system_cpi(instruction: &Instruction, account_infos: &[AccountInfo]) -> ProgramResult {
+    let account = |index: usize| {
+        let key = instruction.accounts[index].pubkey;
+        account_infos.iter().find(|info| *info.key == key).unwrap()
+    };
+    let data = &instruction.data;
+    let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let pubkey_at = |offset: usize| Pubkey::try_from(&data[offset..offset + 32]).unwrap();
+    match u32::from_le_bytes(data[..4].try_into().unwrap()) {
+        // CreateAccount { lamports, space, owner }
+        0 => {
+            move_lamports(account(0), account(1), u64_at(4))?;
+            account(1).resize(u64_at(12) as usize)?;
+            account(1).assign(&pubkey_at(20));
+        }
+        // Assign { owner }
+        1 => account(0).assign(&pubkey_at(4)),
+        // Transfer { lamports }
+        2 => move_lamports(account(0), account(1), u64_at(4))?,
+        // Allocate { space }
+        8 => account(0).resize(u64_at(4) as usize)?,
+        tag => panic!("unsupported system instruction {tag}"),
+    }
+    Ok(())
+}
+
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+    let remaining = from
+        .lamports()
+        .checked_sub(lamports)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    **from.try_borrow_mut_lamports()? = remaining;
+    **to.try_borrow_mut_lamports()? += lamports;
+    Ok(())
+}
+
+pub fn set_time(unix_timestamp: i64) {
+    NOW.with(|now| now.set(unix_timestamp));
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TestAccount {
+    pub lamports: u64,
+    pub owner: Pubkey,
+    pub executable: bool,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Env {
+    pub accounts: HashMap<Pubkey, TestAccount>,
+}
+
+// Account memory laid out as the runtime serializes it: the original data length sits right
+// before the key and the current length right before the data, with room to grow after it,
+// which is what `AccountInfo::resize` relies on.
+struct Region {
+    words: Vec<u64>,
+    owner: Box<Pubkey>,
+    lamports: Box<u64>,
+}
+
+const KEY_OFFSET: usize = 8;
+const DATA_OFFSET: usize = 48;
+
+impl Region {
+    fn new(key: &Pubkey, account: &TestAccount) -> Self {
+        let len = account.data.len();
+        let mut words = vec![0u64; (DATA_OFFSET + len + MAX_PERMITTED_DATA_INCREASE) / 8 + 1];
+        let base = words.as_mut_ptr() as *mut u8;
+        unsafe {
+            *(base.add(4) as *mut u32) = len as u32;
+            std::ptr::copy_nonoverlapping(key.as_ref().as_ptr(), base.add(KEY_OFFSET), 32);
+            *(base.add(DATA_OFFSET - 8) as *mut u64) = len as u64;
+            std::ptr::copy_nonoverlapping(account.data.as_ptr(), base.add(DATA_OFFSET), len);
+        }
+        Region {
+            words,
+            owner: Box::new(account.owner),
+            lamports: Box::new(account.lamports),
+        }
+    }
+
+    fn info<'a>(&'a mut self, is_signer: bool, is_writable: bool, executable: bool) -> AccountInfo<'a> {
+        let base = self.words.as_mut_ptr() as *mut u8;
+        unsafe {
+            let key = &*(base.add(KEY_OFFSET) as *const Pubkey);
+            let len = *(base.add(DATA_OFFSET - 8) as *const u64) as usize;
+            let data = std::slice::from_raw_parts_mut(base.add(DATA_OFFSET), len);
+            let owner = &*(self.owner.as_ref() as *const Pubkey);
+            let lamports = &mut *(self.lamports.as_mut() as *mut u64);
+            AccountInfo::new(key, is_signer, is_writable, lamports, data, owner, executable, 0)
+        }
+    }
+}
+
+impl Env {
+    pub fn new() -> Self {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(Stubs));
+        });
+        set_time(START_TIME);
+
+        let mut env = Env::default();
+        for program in [x3_pepe::ID, system_program::ID, spl_token::ID] {
+            env.accounts.insert(
+                program,
+                TestAccount {
+                    lamports: 1,
+                    owner: bpf_loader_upgradeable::ID,
+                    executable: true,
+                    data: Vec::new(),
+                },
+            );
+        }
+        // Rent { lamports_per_byte_year, exemption_threshold, burn_percent } in bincode
+        let rent = Rent::default();
+        let mut rent_data = rent.lamports_per_byte_year.to_le_bytes().to_vec();
+        rent_data.extend_from_slice(&rent.exemption_threshold.to_le_bytes());
+        rent_data.push(rent.burn_percent);
+        env.accounts.insert(
+            sysvar::rent::ID,
+            TestAccount {
+                lamports: 1,
+                owner: sysvar::ID,
+                executable: false,
+                data: rent_data,
+            },
+        );
+        env
+    }
+
+    /// Runs one instruction atomically: account changes are kept only if it succeeds.
+    pub fn process(&mut self, instruction: Instruction, signers: &[Pubkey]) -> std::result::Result<(), ProgramError> {
+        let mut keys: Vec<Pubkey> = Vec::new();
+        for meta in instruction.accounts.iter() {
+            if !keys.contains(&meta.pubkey) {
+                keys.push(meta.pubkey);
+            }
+        }
+        let mut regions: Vec<Region> = keys
+            .iter()
+            .map(|key| Region::new(key, &self.accounts.get(key).cloned().unwrap_or_default()))
+            .collect();
+
+        let result = {
+            let unique: Vec<AccountInfo> = regions
+                .iter_mut()
+                .zip(keys.iter())
+                .map(|(region, key)| {
+                    let metas = instruction.accounts.iter().filter(|meta| meta.pubkey == *key);
+                    let is_signer = signers.contains(key) || metas.clone().any(|meta| meta.is_signer);
+                    let is_writable = metas.clone().any(|meta| meta.is_writable);
+                    let executable = self.accounts.get(key).map(|a| a.executable).unwrap_or_default();
+                    region.info(is_signer, is_writable, executable)
+                })
+                .collect();
+            let infos: Vec<AccountInfo> = instruction
+                .accounts
+                .iter()
+                .map(|meta| unique[keys.iter().position(|key| *key == meta.pubkey).unwrap()].clone())
+                .collect();
+
+            let result = x3_pepe::entry(&instruction.program_id, &infos, &instruction.data);
+            if result.is_ok() {
+                for info in unique.iter() {
+                    self.accounts.insert(
+                        *info.key,
+                        TestAccount {
+                            lamports: info.lamports(),
+                            owner: *info.owner,
+                            executable: info.executable,
+                            data: info.try_borrow_data().unwrap().to_vec(),
+                        },
+                    );
+                }
+            }
+            result
+        };
+        drop(regions);
+        result
+    }
+
+    pub fn fund(&mut self, wallet: Pubkey) {
+        self.accounts.insert(
+            wallet,
+            TestAccount {
+                lamports: WALLET_LAMPORTS,
+                owner: system_program::ID,
+                ..TestAccount::default()
+            },
+        );
+    }
+
+    pub fn lamports(&self, key: &Pubkey) -> u64 {
+        self.accounts.get(key).map(|a| a.lamports).unwrap_or_default()
+    }
+
+    pub fn read<T: AccountDeserialize>(&self, key: &Pubkey) -> T {
+        let data = &self.accounts.get(key).expect("account exists").data;
+        T::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    pub fn write<T: AccountSerialize>(&mut self, key: Pubkey, value: &T, space: usize) {
+        let mut data = Vec::new();
+        value.try_serialize(&mut data).unwrap();
+        assert!(data.len() <= space);
+        data.resize(space, 0);
+        self.accounts.insert(
+            key,
+            TestAccount {
+                lamports: Rent::default().minimum_balance(space),
+                owner: x3_pepe::ID,
+                executable: false,
+                data,
+            },
+        );
+    }
+}
+
+pub fn error_code(error: u32) -> ProgramError {
+    ProgramError::Custom(anchor_lang::error::ERROR_CODE_OFFSET + error)
+}
+
+pub fn user_pda(wallet: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"user", wallet.as_ref()], &x3_pepe::ID).0
+}
+
+pub fn level(level_bought_time: u64) -> X3 {
+    X3 {
+        blocked: false,
+        referrals: 0,
+        cycles: 0,
+        freeze: 0,
+        level_bought_time,
+        close_level_time: 0,
+        first_line: 0,
+    }
+}
+
+/// A SOL-mode game 0 created directly in account state: the root has every level, no staking
+/// pool, treasury or fees, and the default burn.
+pub struct Game {
+    pub owner: Pubkey,
+    pub root: Pubkey,
+    pub global_state: Pubkey,
+    pub sol_vault: Pubkey,
+    pub staking_token_account: Pubkey,
+    pub prices: Vec<u64>,
+    // Every user PDA, passed as remaining accounts so any upline can be found
+    pub users: Vec<Pubkey>,
+}
+
+impl Game {
+    pub fn new(env: &mut Env, prices: Vec<u64>, matrix_types: Vec<MatrixType>) -> Self {
+        let owner = Pubkey::new_unique();
+        let root = Pubkey::new_unique();
+        let (global_state, bump) = Pubkey::find_program_address(&[b"state"], &x3_pepe::ID);
+        let (sol_vault, sol_vault_bump) = Pubkey::find_program_address(&[b"sol_vault"], &x3_pepe::ID);
+        let (root_account, root_bump) = Pubkey::find_program_address(&[b"user", root.as_ref()], &x3_pepe::ID);
+        let staking_token_account = Pubkey::new_unique();
+        let max_level = prices.len() as u8;
+
+        let global = GlobalState {
+            root,
+            owner,
+            token_mint: Pubkey::default(),
+            paused: false,
+            bump,
+            root_bump,
+            max_level,
+            prices: prices.clone(),
+            vault_token_account: sol_vault,
+            staking_token_account,
+            staking_pool: Pubkey::default(),
+            sol_vault_bump,
+            game_id: 0,
+            matrix_types,
+            slot_shares: Vec::new(),
+            burn_bps: x3_pepe::DEFAULT_BURN_BPS,
+            burn_destination: BurnDestination::Staking,
+            treasury: Pubkey::default(),
+            protocol_fee_bps: 0,
+            staking_pool_unlock_time: 0,
+            version: x3_pepe::GLOBAL_STATE_VERSION,
+        };
+        env.write(global_state, &global, 8 + GlobalState::MAX_SIZE);
+
+        let root_user = UserAccount {
+            wallet: root,
+            referrer: Pubkey::default(),
+            balance: 0,
+            matrix: (0..max_level).map(|_| level(START_TIME as u64)).collect(),
+            closed_cycles: Vec::new(),
+            bump: root_bump,
+            auto_upgrade: false,
+        };
+        env.write(root_account, &root_user, 8 + UserAccount::max_size(max_level as usize));
+
+        env.accounts.insert(
+            sol_vault,
+            TestAccount {
+                lamports: Rent::default().minimum_balance(0),
+                owner: system_program::ID,
+                ..TestAccount::default()
+            },
+        );
+
+        let mut token_data = vec![0u8; SplAccount::LEN];
+        SplAccount {
+            mint: spl_token::native_mint::ID,
+            owner: Pubkey::new_unique(),
+            state: AccountState::Initialized,
+            ..SplAccount::default()
+        }
+        .pack_into_slice(&mut token_data);
+        env.accounts.insert(
+            staking_token_account,
+            TestAccount {
+                lamports: Rent::default().minimum_balance(SplAccount::LEN),
+                owner: spl_token::ID,
+                executable: false,
+                data: token_data,
+            },
+        );
+
+        Game {
+            owner,
+            root,
+            global_state,
+            sol_vault,
+            staking_token_account,
+            prices,
+            users: vec![root_account],
+        }
+    }
+
+    pub fn global(&self, env: &Env) -> GlobalState {
+        env.read(&self.global_state)
+    }
+
+    pub fn user(&self, env: &Env, wallet: &Pubkey) -> UserAccount {
+        env.read(&user_pda(wallet))
+    }
+
+    pub fn update_global(&self, env: &mut Env, update: impl FnOnce(&mut GlobalState)) {
+        let mut global = self.global(env);
+        update(&mut global);
+        env.write(self.global_state, &global, 8 + GlobalState::MAX_SIZE);
+    }
+
+    pub fn update_user(&self, env: &mut Env, wallet: &Pubkey, update: impl FnOnce(&mut UserAccount)) {
+        let mut user = self.user(env, wallet);
+        update(&mut user);
+        let space = env.accounts[&user_pda(wallet)].data.len();
+        env.write(user_pda(wallet), &user, space);
+    }
+
+    fn instruction(&self, accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+        let mut metas = accounts.to_account_metas(None);
+        metas.extend(
+            self.users
+                .iter()
+                .map(|user| AccountMeta::new(*user, false)),
+        );
+        Instruction {
+            program_id: x3_pepe::ID,
+            accounts: metas,
+            data: data.data(),
+        }
+    }
+
+    /// Registers a new funded wallet under `referrer`, paid by the new user.
+    pub fn register(&mut self, env: &mut Env, referrer: &Pubkey) -> Pubkey {
+        self.try_register(env, referrer).expect("registration")
+    }
+
+    pub fn try_register(&mut self, env: &mut Env, referrer: &Pubkey) -> std::result::Result<Pubkey, ProgramError> {
+        let user = Pubkey::new_unique();
+        env.fund(user);
+        let instruction = self.instruction(
+            x3_pepe::accounts::Registration {
+                payer: user,
+                global_state: self.global_state,
+                user_account: user_pda(&user),
+                referrer_account: user_pda(referrer),
+                root_account: user_pda(&self.root),
+                payer_token_account: None,
+                vault_token_account: None,
+                staking_token_account: self.staking_token_account,
+                token_mint: None,
+                sol_vault: Some(self.sol_vault),
+                treasury: None,
+                token_program: spl_token::ID,
+                system_program: system_program::ID,
+                rent: sysvar::rent::ID,
+            },
+            x3_pepe::instruction::Registration {
+                user_key: user,
+                referrer_key: *referrer,
+            },
+        );
+        env.process(instruction, &[user])?;
+        self.users.push(user_pda(&user));
+        Ok(user)
+    }
+
+    fn buy_accounts(&self, payer: Pubkey, user: &Pubkey) -> x3_pepe::accounts::BuyNewLevel {
+        x3_pepe::accounts::BuyNewLevel {
+            payer,
+            global_state: self.global_state,
+            user_account: user_pda(user),
+            root_account: user_pda(&self.root),
+            payer_token_account: None,
+            vault_token_account: None,
+            staking_token_account: self.staking_token_account,
+            token_mint: None,
+            sol_vault: Some(self.sol_vault),
+            treasury: None,
+            token_program: spl_token::ID,
+            system_program: system_program::ID,
+        }
+    }
+
+    pub fn buy_new_level(
+        &self,
+        env: &mut Env,
+        user: &Pubkey,
+        level: u8,
+        source: PaymentSource,
+    ) -> std::result::Result<(), ProgramError> {
+        let instruction = self.instruction(
+            self.buy_accounts(*user, user),
+            x3_pepe::instruction::BuyNewLevel {
+                user_key: *user,
+                level,
+                source,
+            },
+        );
+        env.process(instruction, &[*user])
+    }
+
+    /// Runs the auto-upgrade crank for `user`, signed and paid by `cranker`.
+    pub fn auto_upgrade(&self, env: &mut Env, cranker: &Pubkey, user: &Pubkey) -> std::result::Result<(), ProgramError> {
+        let instruction = self.instruction(
+            self.buy_accounts(*cranker, user),
+            x3_pepe::instruction::AutoUpgrade { user_key: *user },
+        );
+        env.process(instruction, &[*cranker])
+    }
+
+    pub fn owner_instruction(&self, env: &mut Env, data: impl InstructionData) -> std::result::Result<(), ProgramError> {
+        let instruction = Instruction {
+            program_id: x3_pepe::ID,
+            accounts: x3_pepe::accounts::OwnerOnly {
+                global_state: self.global_state,
+                owner: self.owner,
+            }
+            .to_account_metas(None),
+            data: data.data(),
+        };
+        env.process(instruction, &[self.owner])
+    }
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::*;
+use x3_pepe::{MatrixType, PaymentSource};
+
+const PRICE: u64 = 1_000_000;
+
+fn burn(amount: u64) -> u64 {
+    amount * x3_pepe::DEFAULT_BURN_BPS / 10_000
+}
+
+// Default X4 freezes of the three second line places before the last: two prices split
+// evenly, the rounding remainder on the first places.
+const X4_FREEZES: [u64; 3] = [666_700, 666_700, 666_600];
+
+// Pre-engine X3 rule: the first two places freeze half the price for the receiver, the third
+// cycles, releasing the freeze less 0.5% burn, then freezes the full price and passes the full
+// price up.
+#[test]
+fn x3_cycle_keeps_original_payouts() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE, 2 * PRICE], Vec::new());
+    let root = game.root;
+    let a = game.register(&mut env, &root);
+    assert_eq!(game.user(&env, &a).matrix[0].freeze, PRICE);
+
+    game.register(&mut env, &a);
+    let user = game.user(&env, &a);
+    assert_eq!(user.matrix[0].referrals, 1);
+    assert_eq!(user.matrix[0].freeze, PRICE + PRICE / 2);
+
+    game.register(&mut env, &a);
+    assert_eq!(game.user(&env, &a).matrix[0].freeze, 2 * PRICE);
+
+    let staking_before = env.lamports(&game.staking_token_account);
+    game.register(&mut env, &a);
+    let user = game.user(&env, &a);
+    assert_eq!(user.matrix[0].referrals, 0);
+    assert_eq!(user.matrix[0].cycles, 1);
+    assert_eq!(user.matrix[0].first_line, 0);
+    assert!(user.matrix[0].blocked);
+    assert_eq!(user.balance, 2 * PRICE - burn(2 * PRICE));
+    assert_eq!(user.matrix[0].freeze, PRICE);
+    // The pass-up reaches the root, whose share goes to staking along with the burn
+    assert_eq!(
+        env.lamports(&game.staking_token_account) - staking_before,
+        PRICE + burn(2 * PRICE)
+    );
+}
+
+// A place missed by a referrer without the level goes to the next active upline, which is
+// paid half the price without taking a place.
+#[test]
+fn x3_missed_place_pays_upline_half() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE, 2 * PRICE], Vec::new());
+    let root = game.root;
+    let a = game.register(&mut env, &root);
+    let b = game.register(&mut env, &a);
+    let c = game.register(&mut env, &b);
+    game.buy_new_level(&mut env, &a, 1, PaymentSource::Wallet).unwrap();
+
+    game.buy_new_level(&mut env, &c, 1, PaymentSource::Wallet).unwrap();
+    let user = game.user(&env, &a);
+    assert_eq!(user.balance, PRICE);
+    assert_eq!(user.matrix[1].referrals, 0);
+    assert_eq!(user.matrix[1].freeze, 2 * PRICE);
+}
+
+// X4: the first two places under a receiver spill over to the receiver's upline, the next
+// four pay the receiver and the fourth of them closes the cycle.
+#[test]
+fn x4_first_line_spills_over_to_upline() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE], vec![MatrixType::X4]);
+    let root = game.root;
+    let q = game.register(&mut env, &root);
+    let a = game.register(&mut env, &q);
+    assert_eq!(game.user(&env, &q).matrix[0].first_line, 1);
+    assert_eq!(game.user(&env, &q).matrix[0].referrals, 0);
+
+    game.register(&mut env, &a);
+    let upline = game.user(&env, &q);
+    assert_eq!(game.user(&env, &a).matrix[0].first_line, 1);
+    assert_eq!(game.user(&env, &a).matrix[0].referrals, 0);
+    assert_eq!(upline.matrix[0].referrals, 1);
+    assert_eq!(upline.matrix[0].freeze, PRICE + X4_FREEZES[0]);
+
+    game.register(&mut env, &a);
+    assert_eq!(game.user(&env, &a).matrix[0].first_line, 2);
+    assert_eq!(game.user(&env, &q).matrix[0].referrals, 2);
+    assert_eq!(game.user(&env, &a).matrix[0].freeze, PRICE);
+}
+
+#[test]
+fn x4_second_line_pays_and_cycles() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE], vec![MatrixType::X4]);
+    let root = game.root;
+    let a = game.register(&mut env, &root);
+    // The root does not spill over, so a's first line fills from its own referrals
+    for _ in 0..2 {
+        game.register(&mut env, &a);
+    }
+    assert_eq!(game.user(&env, &a).matrix[0].first_line, 2);
+
+    let mut freeze = PRICE;
+    for place in 1..=3u8 {
+        game.register(&mut env, &a);
+        let user = game.user(&env, &a);
+        freeze += X4_FREEZES[place as usize - 1];
+        assert_eq!(user.matrix[0].referrals, place);
+        assert_eq!(user.matrix[0].freeze, freeze);
+    }
+    // The cycle so far froze every price paid in but the last
+    assert_eq!(freeze, 3 * PRICE);
+
+    let staking_before = env.lamports(&game.staking_token_account);
+    game.register(&mut env, &a);
+    let user = game.user(&env, &a);
+    let released = freeze;
+    assert_eq!(user.matrix[0].referrals, 0);
+    assert_eq!(user.matrix[0].first_line, 0);
+    assert_eq!(user.matrix[0].cycles, 1);
+    assert_eq!(user.balance, released - burn(released));
+    assert_eq!(user.matrix[0].freeze, PRICE);
+    assert_eq!(
+        env.lamports(&game.staking_token_account) - staking_before,
+        PRICE + burn(released)
+    );
+
+    // The next cycle starts on the first line again
+    game.register(&mut env, &a);
+    assert_eq!(game.user(&env, &a).matrix[0].first_line, 1);
+}
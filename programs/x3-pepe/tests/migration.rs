@@ -0,0 +1,159 @@
+mod common;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{hash::hash, instruction::Instruction};
+use anchor_lang::system_program;
+use anchor_lang::{InstructionData, ToAccountMetas};
+use common::*;
+use x3_pepe::{ContractError, GlobalState, LegacyUserAccount, LegacyX3, NewCycle, PaymentSource, UserAccount};
+
+const PRICE: u64 = 1_000_000;
+
+#[test]
+fn migrate_user_rewrites_legacy_layout() {
+    let mut env = Env::new();
+    let game = Game::new(&mut env, vec![PRICE, 2 * PRICE], Vec::new());
+    let wallet = Pubkey::new_unique();
+    let (pda, bump) = Pubkey::find_program_address(&[b"user", wallet.as_ref()], &x3_pepe::ID);
+    let legacy = LegacyUserAccount {
+        wallet,
+        referrer: game.root,
+        balance: 42,
+        matrix: vec![
+            LegacyX3 {
+                blocked: true,
+                referrals: 2,
+                cycles: 3,
+                freeze: 7,
+                level_bought_time: 100,
+                close_level_time: 200,
+            },
+            LegacyX3 {
+                blocked: false,
+                referrals: 1,
+                cycles: 0,
+                freeze: 9,
+                level_bought_time: 300,
+                close_level_time: 0,
+            },
+        ],
+        closed_cycles: vec![NewCycle { created_time: 400, level: 0 }],
+        bump,
+    };
+    // Legacy accounts carry the default discriminator and 27-byte levels
+    let legacy_size = 8 + 32 + 32 + 8 + 1 + 4 + 2 * 27 + 4 + 10 * 9;
+    let mut data = hash(b"account:UserAccount").to_bytes()[..8].to_vec();
+    legacy.serialize(&mut data).unwrap();
+    data.resize(legacy_size, 0);
+    env.accounts.insert(
+        pda,
+        TestAccount {
+            lamports: Rent::default().minimum_balance(legacy_size),
+            owner: x3_pepe::ID,
+            executable: false,
+            data,
+        },
+    );
+    let payer = Pubkey::new_unique();
+    env.fund(payer);
+
+    let migrate = |env: &mut Env| {
+        env.process(
+            Instruction {
+                program_id: x3_pepe::ID,
+                accounts: x3_pepe::accounts::MigrateUser {
+                    payer,
+                    global_state: game.global_state,
+                    user_account: pda,
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: x3_pepe::instruction::MigrateUser { user_key: wallet }.data(),
+            },
+            &[payer],
+        )
+    };
+    migrate(&mut env).unwrap();
+
+    let user: UserAccount = env.read(&pda);
+    assert_eq!(user.wallet, wallet);
+    assert_eq!(user.referrer, game.root);
+    assert_eq!(user.balance, 42);
+    assert_eq!(user.matrix.len(), 2);
+    assert!(user.matrix[0].blocked);
+    assert_eq!(user.matrix[0].referrals, 2);
+    assert_eq!(user.matrix[0].cycles, 3);
+    assert_eq!(user.matrix[0].freeze, 7);
+    assert_eq!(user.matrix[0].close_level_time, 200);
+    assert_eq!(user.matrix[0].first_line, 0);
+    assert_eq!(user.matrix[1].freeze, 9);
+    assert_eq!(user.matrix[1].level_bought_time, 300);
+    assert_eq!(user.closed_cycles.len(), 1);
+    assert_eq!(user.closed_cycles[0].created_time, 400);
+    assert_eq!(user.bump, bump);
+    assert!(!user.auto_upgrade);
+    let size = env.accounts[&pda].data.len();
+    assert_eq!(size, 8 + UserAccount::max_size(2));
+    assert!(env.lamports(&pda) >= Rent::default().minimum_balance(size));
+
+    assert_eq!(
+        migrate(&mut env),
+        Err(error_code(x3_pepe::ContractError::AlreadyMigrated as u32))
+    );
+}
+
+// A pre-versioning GlobalState reads its newer fields from zeroed slack, so the burn rate
+// comes out as 0 until migrated.
+#[test]
+fn migrate_global_restores_burn_and_grows_account() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE, 2 * PRICE], Vec::new());
+    game.update_global(&mut env, |global| {
+        global.burn_bps = 0;
+        global.version = 0;
+    });
+    // Original allocation: 14 prices reserved at 64 bytes each
+    let legacy_size = 8 + 32 * 3 + 4 + 4 + 14 * 64 + 32 + 32;
+    env.accounts.get_mut(&game.global_state).unwrap().data.truncate(legacy_size);
+    env.fund(game.owner);
+
+    let root = game.root;
+    assert_eq!(
+        game.try_register(&mut env, &root),
+        Err(error_code(ContractError::MigrationRequired as u32))
+    );
+    assert_eq!(
+        game.buy_new_level(&mut env, &root, 1, PaymentSource::Wallet),
+        Err(error_code(ContractError::MigrationRequired as u32))
+    );
+
+    let migrate = |env: &mut Env| {
+        env.process(
+            Instruction {
+                program_id: x3_pepe::ID,
+                accounts: x3_pepe::accounts::MigrateGlobal {
+                    global_state: game.global_state,
+                    owner: game.owner,
+                    system_program: system_program::ID,
+                }
+                .to_account_metas(None),
+                data: x3_pepe::instruction::MigrateGlobal {}.data(),
+            },
+            &[game.owner],
+        )
+    };
+    migrate(&mut env).unwrap();
+
+    let global: GlobalState = game.global(&env);
+    assert_eq!(global.burn_bps, x3_pepe::DEFAULT_BURN_BPS);
+    assert_eq!(global.version, x3_pepe::GLOBAL_STATE_VERSION);
+    assert_eq!(global.prices, vec![PRICE, 2 * PRICE]);
+    assert_eq!(env.accounts[&game.global_state].data.len(), 8 + GlobalState::MAX_SIZE);
+    assert_eq!(
+        migrate(&mut env),
+        Err(error_code(ContractError::AlreadyMigrated as u32))
+    );
+
+    let a = game.register(&mut env, &root);
+    assert_eq!(game.user(&env, &a).matrix.len(), 1);
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::*;
+use x3_pepe::{
+    instruction::SetSlotShares, validate_slot_shares, ContractError, MatrixType, SlotShare,
+};
+
+const PRICE: u64 = 1_000_000;
+
+fn burn(amount: u64) -> u64 {
+    amount * x3_pepe::DEFAULT_BURN_BPS / 10_000
+}
+
+fn slot(freeze_bps: u64, pass_up_bps: u64) -> SlotShare {
+    SlotShare {
+        freeze_bps,
+        pass_up_bps,
+    }
+}
+
+fn shares(slots: &[SlotShare]) -> Vec<(u64, u64)> {
+    slots
+        .iter()
+        .map(|slot| (slot.freeze_bps, slot.pass_up_bps))
+        .collect()
+}
+
+// The default split pays out every price a cycle takes in, so it passes the setter's check
+#[test]
+fn default_split_matches_matrix_type() {
+    let mut env = Env::new();
+    let game = Game::new(&mut env, vec![PRICE, 2 * PRICE], vec![MatrixType::X3, MatrixType::X4]);
+    let global = game.global(&env);
+    let x3 = global.slot_shares(0);
+    assert_eq!(
+        shares(&x3),
+        vec![(5_000, 0), (5_000, 0), (10_000, 10_000)]
+    );
+    let x4 = global.slot_shares(1);
+    assert_eq!(
+        shares(&x4),
+        vec![(6_667, 0), (6_667, 0), (6_666, 0), (10_000, 10_000)]
+    );
+
+    for (level, slots) in [x3, x4].into_iter().enumerate() {
+        validate_slot_shares(&slots).unwrap();
+        game.owner_instruction(
+            &mut env,
+            SetSlotShares {
+                level: level as u8,
+                slots,
+            },
+        )
+        .unwrap();
+    }
+}
+
+// Two paying places: the first freezes 70% and passes 30% up, the second closes the cycle
+#[test]
+fn custom_split_pays_and_cycles() {
+    let mut env = Env::new();
+    let mut game = Game::new(&mut env, vec![PRICE, 2 * PRICE], Vec::new());
+    let root = game.root;
+    let a = game.register(&mut env, &root);
+    game.owner_instruction(
+        &mut env,
+        SetSlotShares {
+            level: 0,
+            slots: vec![slot(7_000, 3_000), slot(10_000, 0)],
+        },
+    )
+    .unwrap();
+
+    // The pass-up reaches the root, whose share goes to staking
+    let staking_before = env.lamports(&game.staking_token_account);
+    game.register(&mut env, &a);
+    let user = game.user(&env, &a);
+    assert_eq!(user.matrix[0].referrals, 1);
+    assert_eq!(user.matrix[0].freeze, PRICE + 7 * PRICE / 10);
+    assert_eq!(
+        env.lamports(&game.staking_token_account) - staking_before,
+        3 * PRICE / 10
+    );
+
+    let staking_before = env.lamports(&game.staking_token_account);
+    game.register(&mut env, &a);
+    let user = game.user(&env, &a);
+    let released = PRICE + 7 * PRICE / 10;
+    assert_eq!(user.matrix[0].referrals, 0);
+    assert_eq!(user.matrix[0].cycles, 1);
+    assert!(user.matrix[0].blocked);
+    assert_eq!(user.balance, released - burn(released));
+    assert_eq!(user.matrix[0].freeze, PRICE);
+    assert_eq!(
+        env.lamports(&game.staking_token_account) - staking_before,
+        burn(released)
+    );
+
+    // An empty list restores the default three places
+    game.owner_instruction(
+        &mut env,
+        SetSlotShares {
+            level: 0,
+            slots: Vec::new(),
+        },
+    )
+    .unwrap();
+    assert_eq!(game.global(&env).slot_shares(0).len(), 3);
+}
+
+#[test]
+fn invalid_split_is_rejected() {
+    let mut env = Env::new();
+    let game = Game::new(&mut env, vec![PRICE], Vec::new());
+    for slots in [
+        vec![slot(7_000, 2_000)],
+        vec![slot(12_000, 0), slot(8_000, 0)],
+        vec![slot(5_000, 5_000); 7],
+    ] {
+        assert_eq!(
+            game.owner_instruction(&mut env, SetSlotShares { level: 0, slots }),
+            Err(error_code(ContractError::InvalidSlotShares as u32))
+        );
+    }
+    assert_eq!(
+        game.owner_instruction(
+            &mut env,
+            SetSlotShares {
+                level: 1,
+                slots: Vec::new(),
+            },
+        ),
+        Err(error_code(ContractError::LevelOutOfRange as u32))
+    );
+}